@@ -0,0 +1,4 @@
+fn main() {
+    // 迁移脚本通过 embed_migrations! 编译进二进制，变更时需要重新编译
+    println!("cargo:rerun-if-changed=migrations");
+}
@@ -0,0 +1,8 @@
+CREATE TABLE catalog_models (
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    alias TEXT NOT NULL UNIQUE,
+    upstream_model TEXT NOT NULL,
+    owned_by TEXT NOT NULL,
+    context_window INTEGER,
+    created BIGINT NOT NULL
+);
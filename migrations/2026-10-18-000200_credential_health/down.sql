@@ -0,0 +1,8 @@
+ALTER TABLE credentials DROP COLUMN last_check_result;
+ALTER TABLE credentials DROP COLUMN last_check_latency_ms;
+ALTER TABLE credentials DROP COLUMN last_checked_at;
+ALTER TABLE credentials DROP COLUMN last_error;
+ALTER TABLE credentials DROP COLUMN failure_count;
+ALTER TABLE credentials DROP COLUMN cooldown_until;
+ALTER TABLE credentials DROP COLUMN status;
+ALTER TABLE credentials DROP COLUMN weight;
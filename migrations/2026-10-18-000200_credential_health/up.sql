@@ -0,0 +1,8 @@
+ALTER TABLE credentials ADD COLUMN weight INTEGER NOT NULL DEFAULT 1;
+ALTER TABLE credentials ADD COLUMN status TEXT NOT NULL DEFAULT 'active';
+ALTER TABLE credentials ADD COLUMN cooldown_until TIMESTAMP;
+ALTER TABLE credentials ADD COLUMN failure_count INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE credentials ADD COLUMN last_error TEXT;
+ALTER TABLE credentials ADD COLUMN last_checked_at TIMESTAMP;
+ALTER TABLE credentials ADD COLUMN last_check_latency_ms INTEGER;
+ALTER TABLE credentials ADD COLUMN last_check_result TEXT;
@@ -0,0 +1,8 @@
+ALTER TABLE api_tokens ADD COLUMN name TEXT NOT NULL DEFAULT '';
+ALTER TABLE api_tokens ADD COLUMN owner TEXT NOT NULL DEFAULT '';
+ALTER TABLE api_tokens ADD COLUMN last_used_at TIMESTAMP;
+ALTER TABLE api_tokens ADD COLUMN expires_at TIMESTAMP;
+ALTER TABLE api_tokens ADD COLUMN revoked_at TIMESTAMP;
+
+-- 既有的匿名 Token 给一个可辨认的名称
+UPDATE api_tokens SET name = 'key-' || id WHERE name = '';
@@ -0,0 +1,17 @@
+-- 哈希不可逆：回滚后已哈希的 Key 无法再使用，token 列以前缀占位
+CREATE TABLE api_tokens_old (
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    token TEXT NOT NULL,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    name TEXT NOT NULL DEFAULT '',
+    owner TEXT NOT NULL DEFAULT '',
+    last_used_at TIMESTAMP,
+    expires_at TIMESTAMP,
+    revoked_at TIMESTAMP
+);
+
+INSERT INTO api_tokens_old (id, token, created_at, name, owner, last_used_at, expires_at, revoked_at)
+SELECT id, COALESCE(legacy_token, token_prefix), created_at, name, owner, last_used_at, expires_at, revoked_at FROM api_tokens;
+
+DROP TABLE api_tokens;
+ALTER TABLE api_tokens_old RENAME TO api_tokens;
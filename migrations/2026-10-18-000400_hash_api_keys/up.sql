@@ -0,0 +1,22 @@
+-- API Key 只保存带密钥的哈希与可见前缀。SQLite 无法修改列约束，因此重建表；
+-- 原明文暂存于 legacy_token，由程序启动时计算哈希后清空。
+CREATE TABLE api_tokens_new (
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    legacy_token TEXT,
+    token_prefix TEXT NOT NULL DEFAULT '',
+    token_hash TEXT NOT NULL DEFAULT '',
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    name TEXT NOT NULL DEFAULT '',
+    owner TEXT NOT NULL DEFAULT '',
+    last_used_at TIMESTAMP,
+    expires_at TIMESTAMP,
+    revoked_at TIMESTAMP
+);
+
+INSERT INTO api_tokens_new (id, legacy_token, created_at, name, owner, last_used_at, expires_at, revoked_at)
+SELECT id, token, created_at, name, owner, last_used_at, expires_at, revoked_at FROM api_tokens;
+
+DROP TABLE api_tokens;
+ALTER TABLE api_tokens_new RENAME TO api_tokens;
+
+CREATE INDEX api_tokens_token_prefix ON api_tokens (token_prefix);
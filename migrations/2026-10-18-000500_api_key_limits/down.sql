@@ -0,0 +1,3 @@
+ALTER TABLE api_tokens DROP COLUMN max_concurrent;
+ALTER TABLE api_tokens DROP COLUMN tpm_limit;
+ALTER TABLE api_tokens DROP COLUMN rpm_limit;
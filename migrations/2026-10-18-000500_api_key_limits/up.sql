@@ -0,0 +1,4 @@
+-- 每个 API Key 的限流配置，NULL 表示不限制
+ALTER TABLE api_tokens ADD COLUMN rpm_limit INTEGER;
+ALTER TABLE api_tokens ADD COLUMN tpm_limit INTEGER;
+ALTER TABLE api_tokens ADD COLUMN max_concurrent INTEGER;
@@ -0,0 +1,20 @@
+-- 每个代理请求一行，用于内部计费与故障排查。
+-- 不对 api_tokens / credentials 建外键：删除凭据后历史记录仍需保留。
+CREATE TABLE request_log (
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    created_at TIMESTAMP NOT NULL,
+    api_token_id INTEGER,
+    principal TEXT NOT NULL,
+    credential_id INTEGER,
+    model TEXT NOT NULL,
+    prompt_tokens INTEGER NOT NULL DEFAULT 0,
+    completion_tokens INTEGER NOT NULL DEFAULT 0,
+    latency_ms INTEGER NOT NULL,
+    upstream_status INTEGER,
+    stream BOOLEAN NOT NULL,
+    attempts INTEGER NOT NULL,
+    error TEXT
+);
+
+CREATE INDEX idx_request_log_created_at ON request_log (created_at);
+CREATE INDEX idx_request_log_api_token_id ON request_log (api_token_id);
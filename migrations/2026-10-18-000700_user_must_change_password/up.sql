@@ -0,0 +1,2 @@
+-- 为 1 时登录后必须先修改密码（如首次启动生成的初始密码）
+ALTER TABLE users ADD COLUMN must_change_password BOOLEAN NOT NULL DEFAULT 0;
@@ -0,0 +1,5 @@
+-- 管理后台角色：owner（全部权限）、operator（管理凭据与 API Key）、viewer（只读）
+-- 已有用户（即初始 admin）成为 owner
+ALTER TABLE users ADD COLUMN role TEXT NOT NULL DEFAULT 'owner';
+-- 非空表示已停用，停用的用户无法登录，已签发的会话也随即失效
+ALTER TABLE users ADD COLUMN disabled_at TIMESTAMP;
@@ -0,0 +1,14 @@
+-- TOTP 两步验证：密钥以主密钥信封加密保存；totp_enabled_at 为空表示未启用或尚未完成绑定
+ALTER TABLE users ADD COLUMN totp_secret TEXT;
+ALTER TABLE users ADD COLUMN totp_enabled_at TIMESTAMP;
+-- 最近一次通过验证的时间步，防止同一验证码被重复使用
+ALTER TABLE users ADD COLUMN totp_last_step BIGINT;
+
+-- 恢复码只保存 Argon2 哈希，每个只能使用一次
+CREATE TABLE user_recovery_codes (
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    code_hash TEXT NOT NULL,
+    used_at TIMESTAMP
+);
+CREATE INDEX idx_user_recovery_codes_user ON user_recovery_codes(user_id);
@@ -0,0 +1,15 @@
+-- 登录失败过多触发的临时锁定，供管理员查看；锁定状态本身保存在进程内
+CREATE TABLE login_lockouts (
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    created_at TIMESTAMP NOT NULL,
+    -- user：按用户名锁定；ip：按来源 IP 锁定
+    scope TEXT NOT NULL,
+    subject TEXT NOT NULL,
+    -- 触发锁定的那次尝试的来源 IP
+    ip TEXT NOT NULL,
+    failures INTEGER NOT NULL,
+    locked_until TIMESTAMP NOT NULL,
+    -- 管理员手动解除的时间
+    cleared_at TIMESTAMP
+);
+CREATE INDEX idx_login_lockouts_created_at ON login_lockouts(created_at);
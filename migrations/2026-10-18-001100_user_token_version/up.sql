@@ -0,0 +1,2 @@
+-- 每次修改或重置密码时递增；签发的 Token 带有该值，旧值的 Token 随即失效
+ALTER TABLE users ADD COLUMN token_version INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,122 @@
+//! 请求记账：每个代理请求结束后生成一条 `request_log` 记录，
+//! 由后台任务批量写库，避免拖慢响应路径
+
+use std::time::Instant;
+
+use actix_web::http::StatusCode;
+use chrono::Utc;
+use tokio::sync::mpsc::{self, error::TrySendError};
+
+use crate::auth::Principal;
+use crate::db::{self, DbPool};
+use crate::handlers::Usage;
+use crate::metrics;
+use crate::models::NewRequestLog;
+use crate::services;
+
+/// 待写入队列容量；队列满时丢弃新记录而不阻塞请求
+const QUEUE_CAPACITY: usize = 10_000;
+/// 单次批量写入的最大条数
+const BATCH_SIZE: usize = 200;
+
+/// 记账入口，通过 `web::Data` 共享
+#[derive(Clone)]
+pub struct RequestLogger {
+    tx: mpsc::Sender<NewRequestLog>,
+}
+
+impl RequestLogger {
+    /// 启动后台写库任务
+    pub fn spawn(pool: DbPool) -> Self {
+        let (tx, mut rx) = mpsc::channel::<NewRequestLog>(QUEUE_CAPACITY);
+        actix_web::rt::spawn(async move {
+            let mut batch = Vec::with_capacity(BATCH_SIZE);
+            while rx.recv_many(&mut batch, BATCH_SIZE).await > 0 {
+                let entries = std::mem::take(&mut batch);
+                let count = entries.len();
+                if let Err(e) = db::run(&pool, move |pool| services::insert_request_logs(pool, &entries)).await {
+                    log::error!("Failed to write {} request log entries: {}", count, e);
+                }
+            }
+        });
+        Self { tx }
+    }
+
+    /// 提交一条记录，不等待落库
+    pub fn record(&self, entry: NewRequestLog) {
+        match self.tx.try_send(entry) {
+            Ok(()) => {}
+            Err(TrySendError::Full(entry)) => log::warn!("Request log queue full, dropping entry for {}", entry.principal),
+            Err(TrySendError::Closed(_)) => log::error!("Request log writer has stopped"),
+        }
+    }
+}
+
+/// 单个请求的记账状态：随请求推进逐步填写，结束时交给 [`RequestLogger`]
+pub struct RequestRecord {
+    entry: NewRequestLog,
+    started: Instant,
+}
+
+impl RequestRecord {
+    pub fn new(principal: &Principal, model: &str, stream: bool) -> Self {
+        let api_token_id = match principal {
+            Principal::ApiKey { id, .. } => Some(*id),
+            Principal::User { .. } => None,
+        };
+        Self {
+            entry: NewRequestLog {
+                created_at: Utc::now().naive_utc(),
+                api_token_id,
+                principal: principal.to_string(),
+                credential_id: None,
+                model: model.to_string(),
+                prompt_tokens: 0,
+                completion_tokens: 0,
+                latency_ms: 0,
+                upstream_status: None,
+                stream,
+                attempts: 0,
+                error: None,
+            },
+            started: Instant::now(),
+        }
+    }
+
+    /// 开始用某个凭据调用上游
+    pub fn attempt(&mut self, credential_id: i32) {
+        self.entry.attempts += 1;
+        self.entry.credential_id = Some(credential_id);
+        self.entry.upstream_status = None;
+    }
+
+    /// 记录上游响应状态码
+    pub fn upstream_status(&mut self, status: u16) {
+        self.entry.upstream_status = Some(status as i32);
+    }
+
+    /// 请求成功结束
+    pub fn finish(self, logger: &RequestLogger, usage: Usage) {
+        self.submit(logger, StatusCode::OK, usage, None);
+    }
+
+    /// 请求失败结束，`status` 为返回给客户端的状态码
+    pub fn fail(self, logger: &RequestLogger, status: StatusCode, error: &str) {
+        self.submit(logger, status, Usage::default(), Some(error.to_string()));
+    }
+
+    /// 流式响应已开始后上游中途出错：响应头已是 200，按 502 记为失败，同时保留已产生的用量
+    pub fn interrupted(self, logger: &RequestLogger, usage: Usage, error: &str) {
+        self.submit(logger, StatusCode::BAD_GATEWAY, usage, Some(error.to_string()));
+    }
+
+    fn submit(mut self, logger: &RequestLogger, status: StatusCode, usage: Usage, error: Option<String>) {
+        metrics::REQUESTS.with_label_values(&[self.entry.model.as_str(), status.as_str()]).inc();
+        let clamp = |v: u32| v.min(i32::MAX as u32) as i32;
+        self.entry.prompt_tokens = clamp(usage.prompt_tokens);
+        self.entry.completion_tokens = clamp(usage.completion_tokens);
+        self.entry.latency_ms = self.started.elapsed().as_millis().min(i32::MAX as u128) as i32;
+        self.entry.error = error;
+        logger.record(self.entry);
+    }
+}
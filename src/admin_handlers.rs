@@ -1,11 +1,25 @@
-use actix_web::{web, HttpResponse, HttpRequest, Responder};
+use actix_web::cookie::{Cookie, SameSite};
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
 use tera::{Context, Tera};
-use crate::{repository, services, utils, auth};
-use serde::Deserialize;
+use crate::{repository, services, utils, prober, report, totp};
+use crate::auth::{self, Claims, JwtKeys};
+use crate::crypto::MasterKey;
+use crate::login_throttle::{LoginThrottle, Scope};
+use crate::db::{self, DbPool};
+use crate::settings::Settings;
+use crate::models::{ApiToken, Credential, KeyLimits, Role, UsageRow, User};
+use chrono::{Days, NaiveDate, NaiveDateTime, Utc};
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
 
 /// 显示管理员登录页面
 pub async fn show_login(tmpl: web::Data<Tera>) -> impl Responder {
-    let ctx = Context::new();
+    render_login(&tmpl, None)
+}
+
+fn render_login(tmpl: &Tera, error: Option<&str>) -> HttpResponse {
+    let mut ctx = Context::new();
+    ctx.insert("error", &error);
     let rendered = tmpl
         .render("login.html", &ctx)
         .unwrap_or_else(|e| format!("Template error: {e}"));
@@ -14,47 +28,406 @@ pub async fn show_login(tmpl: web::Data<Tera>) -> impl Responder {
         .body(rendered)
 }
 
+/// 登录尝试过于频繁（退避或锁定中）
+fn render_too_many_attempts(tmpl: &Tera, template: &str, username: Option<&str>, wait: std::time::Duration) -> HttpResponse {
+    let secs = auth::retry_after_secs(wait);
+    let mut ctx = Context::new();
+    ctx.insert("username", &username);
+    ctx.insert("error", &auth::too_many_attempts_message(wait));
+    let rendered = tmpl
+        .render(template, &ctx)
+        .unwrap_or_else(|e| format!("Template error: {e}"));
+    HttpResponse::TooManyRequests()
+        .append_header(("Retry-After", secs.to_string()))
+        .content_type("text/html; charset=utf-8")
+        .body(rendered)
+}
+
 #[derive(Deserialize)]
 pub struct LoginForm {
     username: String,
     password: String,
 }
 
-/// 处理登录表单
-pub async fn handle_login(form: web::Form<LoginForm>, tmpl: web::Data<Tera>) -> impl Responder {
-    match repository::get_user_by_username(&form.username) {
-        Ok(user) => {
-            if utils::verify_password(&user.password_hash, &form.password).unwrap_or(false) {
-                // 生成 JWT 并写 Cookie
-                let token = auth::generate_token(&user.username).unwrap();
-                HttpResponse::Found()
-                    .append_header(("Location", "/admin/credentials"))
-                    .cookie(
-                        actix_web::cookie::Cookie::build("admin_jwt", token)
-                            .path("/")
-                            .http_only(true)
-                            .finish(),
-                    )
-                    .finish()
-            } else {
-                render_error(&tmpl, "密码错误")
-            }
+/// 处理登录表单；用户不存在与密码错误返回相同的提示
+pub async fn handle_login(
+    req: HttpRequest,
+    form: web::Form<LoginForm>,
+    tmpl: web::Data<Tera>,
+    pool: web::Data<DbPool>,
+    jwt_keys: web::Data<JwtKeys>,
+    throttle: web::Data<LoginThrottle>,
+    settings: web::Data<Settings>,
+) -> impl Responder {
+    let attempt = match throttle.begin(&throttle.client_ip(&req), &form.username) {
+        Ok(attempt) => attempt,
+        Err(wait) => return render_too_many_attempts(&tmpl, "login.html", None, wait),
+    };
+
+    let LoginForm { username, password } = form.into_inner();
+    let user = match db::run(&pool, move |pool| auth::authenticate(pool, &username, &password)).await {
+        Ok(Some(user)) => user,
+        Ok(None) => {
+            auth::record_login_failure(&pool, attempt, "password").await;
+            return render_login(&tmpl, Some("用户名或密码错误"));
         }
-        Err(_) => render_error(&tmpl, "用户不存在"),
+        Err(e) => return HttpResponse::InternalServerError().body(format!("Error: {e}")),
+    };
+    // 已启用两步验证：先发临时 Token，验证码通过后再签发会话；失败计数待第二步通过后才清除
+    if user.totp_enabled() {
+        let token = jwt_keys.generate_pending_token(&user).unwrap();
+        return HttpResponse::Found()
+            .append_header(("Location", "/admin/login/totp"))
+            .cookie(pending_cookie(token, &settings))
+            .finish();
     }
+    attempt.succeeded();
+    // 生成 JWT 并写 Cookie；初始密码须先修改
+    let token = jwt_keys.generate_token(&user).unwrap();
+    redirect_with_session(landing_page(&user), token, &settings)
 }
 
-/// 显示凭据列表
-pub async fn show_credentials(req: HttpRequest, tmpl: web::Data<Tera>) -> impl Responder {
-    if !check_cookie(&req) {
+/// 第二步登录的临时 Cookie，仅发送到 `/admin/login`
+const PENDING_COOKIE: &str = "admin_2fa";
+
+fn pending_cookie(token: String, settings: &Settings) -> Cookie<'static> {
+    admin_cookie(PENDING_COOKIE, token, "/admin/login", settings)
+}
+
+/// 管理后台 Cookie：`SameSite=Strict` 使跨站发起的表单提交不携带会话，防止 CSRF；
+/// 配置 `server.secure_cookies` 时仅经 HTTPS 发送
+fn admin_cookie(name: &'static str, value: String, path: &'static str, settings: &Settings) -> Cookie<'static> {
+    Cookie::build(name, value)
+        .path(path)
+        .http_only(true)
+        .same_site(SameSite::Strict)
+        .secure(settings.server.secure_cookies)
+        .finish()
+}
+
+/// 登录后的落地页：初始密码须先修改
+fn landing_page(user: &User) -> &'static str {
+    if user.must_change_password { "/admin/password" } else { "/admin/credentials" }
+}
+
+/// 显示第二步登录（TOTP 验证码或恢复码）页面
+pub async fn show_login_totp(req: HttpRequest, tmpl: web::Data<Tera>, jwt_keys: web::Data<JwtKeys>) -> impl Responder {
+    let pending = req.cookie(PENDING_COOKIE).and_then(|c| jwt_keys.validate_pending_token(c.value()).ok());
+    let Some(claims) = pending else {
         return HttpResponse::Found().append_header(("Location", "/admin/login")).finish();
-    }
-    let creds = services::list_credentials().unwrap_or_default();
-    let api = services::current_api_token().unwrap_or(None);
+    };
+    render_login_totp(&tmpl, &claims.sub, None)
+}
 
+fn render_login_totp(tmpl: &Tera, username: &str, error: Option<&str>) -> HttpResponse {
     let mut ctx = Context::new();
+    ctx.insert("username", username);
+    ctx.insert("error", &error);
+    let rendered = tmpl
+        .render("login_totp.html", &ctx)
+        .unwrap_or_else(|e| format!("Template error: {e}"));
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(rendered)
+}
+
+#[derive(Deserialize)]
+pub struct TotpForm {
+    code: String,
+}
+
+/// 校验第二步验证，通过后签发会话并清除临时 Cookie
+#[allow(clippy::too_many_arguments)] // actix extractors
+pub async fn handle_login_totp(
+    req: HttpRequest,
+    form: web::Form<TotpForm>,
+    tmpl: web::Data<Tera>,
+    pool: web::Data<DbPool>,
+    jwt_keys: web::Data<JwtKeys>,
+    master_key: web::Data<MasterKey>,
+    throttle: web::Data<LoginThrottle>,
+    settings: web::Data<Settings>,
+) -> impl Responder {
+    let pending = req.cookie(PENDING_COOKIE).and_then(|c| jwt_keys.validate_pending_token(c.value()).ok());
+    let Some(claims) = pending else {
+        return HttpResponse::Found().append_header(("Location", "/admin/login")).finish();
+    };
+    let attempt = match throttle.begin(&throttle.client_ip(&req), &claims.sub) {
+        Ok(attempt) => attempt,
+        Err(wait) => return render_too_many_attempts(&tmpl, "login_totp.html", Some(&claims.sub), wait),
+    };
+    let (username, token_version) = (claims.sub.clone(), claims.token_version);
+    let code = form.into_inner().code;
+    let master_key = master_key.into_inner();
+    let result = db::run(&pool, move |pool| {
+        let user = repository::get_user_by_username(pool, &username)?;
+        let verified = user.is_active() && user.accepts_token(token_version) && auth::verify_second_factor(pool, &master_key, &user, &code)?;
+        Ok(verified.then_some(user))
+    })
+    .await;
+    match result {
+        Ok(Some(user)) => {
+            attempt.succeeded();
+            let token = jwt_keys.generate_token(&user).unwrap();
+            let mut res = redirect_with_session(landing_page(&user), token, &settings);
+            let mut removal = pending_cookie(String::new(), &settings);
+            removal.make_removal();
+            let _ = res.add_cookie(&removal);
+            res
+        }
+        Ok(None) => {
+            auth::record_login_failure(&pool, attempt, "totp").await;
+            render_login_totp(&tmpl, &claims.sub, Some("验证码错误"))
+        }
+        Err(e) => HttpResponse::InternalServerError().body(format!("Error: {e}")),
+    }
+}
+
+/// 显示两步验证设置页面：未启用时可开始绑定，已启用时可重新生成恢复码或关闭
+pub async fn show_totp(claims: web::ReqData<Claims>, tmpl: web::Data<Tera>, pool: web::Data<DbPool>, master_key: web::Data<MasterKey>) -> impl Responder {
+    render_totp(&tmpl, &pool, &master_key, &claims, None, None).await
+}
+
+/// `recovery_codes` 为刚生成、仅显示一次的恢复码
+async fn render_totp(tmpl: &Tera, pool: &DbPool, master_key: &MasterKey, claims: &Claims, recovery_codes: Option<&[String]>, error: Option<&str>) -> HttpResponse {
+    let username = claims.sub.clone();
+    let user = match db::run(pool, move |pool| repository::get_user_by_username(pool, &username)).await {
+        Ok(user) => user,
+        Err(e) => return HttpResponse::InternalServerError().body(format!("Error: {e}")),
+    };
+    let mut ctx = page_context(claims);
+    ctx.insert("enabled", &user.totp_enabled());
+    ctx.insert("recovery_codes", &recovery_codes);
+    ctx.insert("error", &error);
+    // 已生成但尚未确认的密钥：显示二维码供绑定
+    if let Some(stored) = user.totp_secret.as_deref().filter(|_| !user.totp_enabled()) {
+        match master_key.decrypt(stored) {
+            Ok(secret) => {
+                let uri = totp::provisioning_uri(&user.username, &secret);
+                ctx.insert("secret", &secret);
+                ctx.insert("qr_svg", &totp::qr_svg(&uri));
+                ctx.insert("uri", &uri);
+            }
+            Err(e) => log::error!("Failed to decrypt TOTP secret of {}: {}", user.username, e),
+        }
+    }
+    if user.totp_enabled() {
+        let uid = user.id;
+        let remaining = db::run(pool, move |pool| repository::unused_recovery_codes(pool, uid)).await.map(|c| c.len()).unwrap_or(0);
+        ctx.insert("remaining_codes", &remaining);
+    }
+
+    let rendered = tmpl
+        .render("totp.html", &ctx)
+        .unwrap_or_else(|e| format!("Template error: {e}"));
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(rendered)
+}
+
+/// 生成新的待绑定密钥
+pub async fn start_totp_setup(claims: web::ReqData<Claims>, pool: web::Data<DbPool>, master_key: web::Data<MasterKey>) -> impl Responder {
+    let username = claims.sub.clone();
+    let master_key = master_key.into_inner();
+    let result = db::run(&pool, move |pool| {
+        let user = repository::get_user_by_username(pool, &username)?;
+        let encrypted = master_key.encrypt(&totp::generate_secret())?;
+        repository::set_pending_totp_secret(pool, user.id, &encrypted)
+    })
+    .await;
+    if let Err(e) = result {
+        return HttpResponse::InternalServerError().body(format!("Error: {e}"));
+    }
+    HttpResponse::Found()
+        .append_header(("Location", "/admin/totp"))
+        .finish()
+}
+
+/// 以认证器中的验证码确认绑定，启用两步验证并显示恢复码
+pub async fn confirm_totp(claims: web::ReqData<Claims>, form: web::Form<TotpForm>, tmpl: web::Data<Tera>, pool: web::Data<DbPool>, master_key: web::Data<MasterKey>) -> impl Responder {
+    let username = claims.sub.clone();
+    let code = form.into_inner().code;
+    let key = master_key.clone().into_inner();
+    let result = db::run(&pool, move |pool| {
+        let user = repository::get_user_by_username(pool, &username)?;
+        let Some(stored) = user.totp_secret.as_deref().filter(|_| !user.totp_enabled()) else {
+            return Ok(None);
+        };
+        let secret = key.decrypt(stored)?;
+        let now = Utc::now().timestamp().max(0) as u64;
+        let Some(step) = totp::verify(&secret, &code, now, None) else {
+            return Ok(None);
+        };
+        let codes = totp::generate_recovery_codes();
+        let hashes = codes.iter().map(|c| utils::hash_password(&totp::normalize_recovery_code(c))).collect::<anyhow::Result<Vec<_>>>()?;
+        repository::enable_totp(pool, user.id, step as i64, hashes)?;
+        Ok(Some(codes))
+    })
+    .await;
+    match result {
+        Ok(Some(codes)) => render_totp(&tmpl, &pool, &master_key, &claims, Some(&codes), None).await,
+        Ok(None) => render_totp(&tmpl, &pool, &master_key, &claims, None, Some("验证码错误，请确认手机时间准确后重试")).await,
+        Err(e) => HttpResponse::InternalServerError().body(format!("Error: {e}")),
+    }
+}
+
+/// 以当前 TOTP 验证码换取一组新的恢复码，旧恢复码全部作废
+pub async fn regenerate_recovery_codes(
+    req: HttpRequest,
+    claims: web::ReqData<Claims>,
+    form: web::Form<TotpForm>,
+    tmpl: web::Data<Tera>,
+    pool: web::Data<DbPool>,
+    master_key: web::Data<MasterKey>,
+    throttle: web::Data<LoginThrottle>,
+) -> impl Responder {
+    // 新恢复码可绕过两步验证，验证码校验与登录共用失败计数
+    let attempt = match throttle.begin(&throttle.client_ip(&req), &claims.sub) {
+        Ok(attempt) => attempt,
+        Err(wait) => {
+            return render_totp(&tmpl, &pool, &master_key, &claims, None, Some(&auth::too_many_attempts_message(wait))).await;
+        }
+    };
+    let username = claims.sub.clone();
+    let code = form.into_inner().code;
+    let key = master_key.clone().into_inner();
+    let result = db::run(&pool, move |pool| {
+        let user = repository::get_user_by_username(pool, &username)?;
+        if !totp::is_totp_code(&code) || !auth::verify_second_factor(pool, &key, &user, &code)? {
+            return Ok(None);
+        }
+        let codes = totp::generate_recovery_codes();
+        let hashes = codes.iter().map(|c| utils::hash_password(&totp::normalize_recovery_code(c))).collect::<anyhow::Result<Vec<_>>>()?;
+        repository::replace_recovery_codes(pool, user.id, hashes)?;
+        Ok(Some(codes))
+    })
+    .await;
+    match result {
+        Ok(Some(codes)) => {
+            attempt.succeeded();
+            render_totp(&tmpl, &pool, &master_key, &claims, Some(&codes), None).await
+        }
+        Ok(None) => {
+            auth::record_login_failure(&pool, attempt, "totp").await;
+            render_totp(&tmpl, &pool, &master_key, &claims, None, Some("验证码错误")).await
+        }
+        Err(e) => HttpResponse::InternalServerError().body(format!("Error: {e}")),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct DisableTotpForm {
+    password: String,
+}
+
+/// 验证密码后关闭两步验证（也用于放弃尚未完成的绑定）
+pub async fn disable_totp(
+    req: HttpRequest,
+    claims: web::ReqData<Claims>,
+    form: web::Form<DisableTotpForm>,
+    tmpl: web::Data<Tera>,
+    pool: web::Data<DbPool>,
+    master_key: web::Data<MasterKey>,
+    throttle: web::Data<LoginThrottle>,
+) -> impl Responder {
+    // 密码校验与登录共用失败计数
+    let attempt = match throttle.begin(&throttle.client_ip(&req), &claims.sub) {
+        Ok(attempt) => attempt,
+        Err(wait) => {
+            return render_totp(&tmpl, &pool, &master_key, &claims, None, Some(&auth::too_many_attempts_message(wait))).await;
+        }
+    };
+    let username = claims.sub.clone();
+    let password = form.into_inner().password;
+    let result = db::run(&pool, move |pool| {
+        let user = repository::get_user_by_username(pool, &username)?;
+        if !utils::verify_password(&user.password_hash, &password).unwrap_or(false) {
+            return Ok(false);
+        }
+        repository::disable_totp(pool, user.id)?;
+        Ok(true)
+    })
+    .await;
+    match result {
+        Ok(true) => {
+            attempt.succeeded();
+            HttpResponse::Found()
+                .append_header(("Location", "/admin/totp"))
+                .finish()
+        }
+        Ok(false) => {
+            auth::record_login_failure(&pool, attempt, "password").await;
+            render_totp(&tmpl, &pool, &master_key, &claims, None, Some("密码错误")).await
+        }
+        Err(e) => HttpResponse::InternalServerError().body(format!("Error: {e}")),
+    }
+}
+
+/// 显示修改密码页面；须先修改密码的会话也可访问
+pub async fn show_change_password(claims: web::ReqData<Claims>, tmpl: web::Data<Tera>) -> impl Responder {
+    render_change_password(&tmpl, &claims, None)
+}
+
+fn render_change_password(tmpl: &Tera, claims: &Claims, error: Option<&str>) -> HttpResponse {
+    let mut ctx = page_context(claims);
+    ctx.insert("forced", &claims.must_change_password);
+    ctx.insert("error", &error);
+    ctx.insert("min_length", &utils::MIN_PASSWORD_LEN);
+    let rendered = tmpl
+        .render("change_password.html", &ctx)
+        .unwrap_or_else(|e| format!("Template error: {e}"));
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(rendered)
+}
+
+#[derive(Deserialize)]
+pub struct ChangePasswordForm {
+    old_password: String,
+    new_password: String,
+    confirm_password: String,
+}
+
+/// 处理修改密码表单，成功后重新签发会话
+#[allow(clippy::too_many_arguments)] // actix extractors
+pub async fn handle_change_password(
+    req: HttpRequest,
+    claims: web::ReqData<Claims>,
+    form: web::Form<ChangePasswordForm>,
+    tmpl: web::Data<Tera>,
+    pool: web::Data<DbPool>,
+    jwt_keys: web::Data<JwtKeys>,
+    throttle: web::Data<LoginThrottle>,
+    settings: web::Data<Settings>,
+) -> impl Responder {
+    let form = form.into_inner();
+    if form.new_password != form.confirm_password {
+        return render_change_password(&tmpl, &claims, Some("两次输入的新密码不一致"));
+    }
+    let ip = throttle.client_ip(&req);
+    match auth::change_password(&pool, &throttle, &ip, claims.sub.clone(), form.old_password, form.new_password).await {
+        Ok(user) => match jwt_keys.generate_token(&user) {
+            Ok(token) => redirect_with_session("/admin/credentials", token, &settings),
+            Err(e) => HttpResponse::InternalServerError().body(format!("Error: {e}")),
+        },
+        Err(e) => render_change_password(&tmpl, &claims, Some(&e.message())),
+    }
+}
+
+/// 显示凭据列表
+pub async fn show_credentials(claims: web::ReqData<Claims>, tmpl: web::Data<Tera>, pool: web::Data<DbPool>) -> impl Responder {
+    let now = Utc::now().naive_utc();
+    let creds: Vec<CredentialView> = db::run(&pool, services::list_credentials)
+        .await
+        .unwrap_or_default()
+        .into_iter()
+        .map(|c| CredentialView::new(c, now))
+        .collect();
+    let models = db::run(&pool, services::list_models).await.unwrap_or_default();
+
+    let mut ctx = page_context(&claims);
     ctx.insert("credentials", &creds);
-    ctx.insert("api_token", &api);
+    ctx.insert("models", &models);
 
     let rendered = tmpl
         .render("credentials.html", &ctx)
@@ -64,16 +437,33 @@ pub async fn show_credentials(req: HttpRequest, tmpl: web::Data<Tera>) -> impl R
         .body(rendered)
 }
 
+/// 凭据列表行，附带当前健康状态
+#[derive(Serialize)]
+struct CredentialView {
+    #[serde(flatten)]
+    credential: Credential,
+    state: &'static str,
+}
+
+impl CredentialView {
+    fn new(credential: Credential, now: NaiveDateTime) -> Self {
+        let state = credential.state(now);
+        Self { credential, state }
+    }
+}
+
 #[derive(Deserialize)]
 pub struct CredentialForm {
     email: String,
     token: String,
+    weight: Option<String>,
 }
 
-pub async fn add_credential(req: HttpRequest, form: web::Form<CredentialForm>) -> impl Responder {
-    if !check_cookie(&req) { return HttpResponse::Found().append_header(("Location", "/admin/login")).finish(); }
-
-    if let Err(e) = services::create_credential(&form.email, &form.token) {
+pub async fn add_credential(form: web::Form<CredentialForm>, pool: web::Data<DbPool>, master_key: web::Data<MasterKey>) -> impl Responder {
+    let weight = form.weight.as_deref().and_then(|v| v.trim().parse::<i32>().ok()).unwrap_or(1);
+    let form = form.into_inner();
+    let master_key = master_key.into_inner();
+    if let Err(e) = db::run(&pool, move |pool| services::create_credential(pool, &master_key, &form.email, &form.token, weight)).await {
         return HttpResponse::InternalServerError().body(format!("Error: {e}"));
     }
     HttpResponse::Found()
@@ -81,33 +471,419 @@ pub async fn add_credential(req: HttpRequest, form: web::Form<CredentialForm>) -
         .finish()
 }
 
-pub async fn delete_credential(req: HttpRequest, path: web::Path<i32>) -> impl Responder {
-    if !check_cookie(&req) { return HttpResponse::Found().append_header(("Location", "/admin/login")).finish(); }
+pub async fn delete_credential(path: web::Path<i32>, pool: web::Data<DbPool>) -> impl Responder {
+    let cid = path.into_inner();
+    let _ = db::run(&pool, move |pool| services::remove_credential(pool, cid)).await;
+    HttpResponse::Found()
+        .append_header(("Location", "/admin/credentials"))
+        .finish()
+}
+
+pub async fn enable_credential(path: web::Path<i32>, pool: web::Data<DbPool>) -> impl Responder {
+    let cid = path.into_inner();
+    let _ = db::run(&pool, move |pool| services::enable_credential(pool, cid)).await;
+    HttpResponse::Found()
+        .append_header(("Location", "/admin/credentials"))
+        .finish()
+}
 
+/// 立即对单个凭据执行一次健康检查
+pub async fn test_credential(path: web::Path<i32>, client: web::Data<Client>, pool: web::Data<DbPool>, master_key: web::Data<MasterKey>, settings: web::Data<Settings>) -> impl Responder {
     let cid = path.into_inner();
-    let _ = services::remove_credential(cid);
+    if let Ok(Some(credential)) = db::run(&pool, move |pool| services::get_credential(pool, cid)).await {
+        if let Err(e) = prober::probe(&client, &pool, &master_key, &settings, &credential).await {
+            log::error!("Probe for {} failed: {}", credential.email, e);
+        }
+    }
     HttpResponse::Found()
         .append_header(("Location", "/admin/credentials"))
         .finish()
 }
 
-pub async fn generate_api_token(req: HttpRequest) -> impl Responder {
-    if !check_cookie(&req) { return HttpResponse::Found().append_header(("Location", "/admin/login")).finish(); }
+/// API Key 列表行，附带当前状态
+#[derive(Serialize)]
+struct ApiKeyView {
+    #[serde(flatten)]
+    key: ApiToken,
+    state: &'static str,
+}
 
-    let _ = services::generate_api_token();
+impl ApiKeyView {
+    fn new(key: ApiToken, now: NaiveDateTime) -> Self {
+        let state = key.state(now);
+        Self { key, state }
+    }
+}
+
+/// 显示 API Key 列表
+pub async fn show_api_keys(claims: web::ReqData<Claims>, tmpl: web::Data<Tera>, pool: web::Data<DbPool>) -> impl Responder {
+    render_api_keys(&tmpl, &pool, &claims, None).await
+}
+
+async fn render_api_keys(tmpl: &Tera, pool: &DbPool, claims: &Claims, new_key: Option<&str>) -> HttpResponse {
+    let now = Utc::now().naive_utc();
+    let keys: Vec<ApiKeyView> = db::run(pool, services::list_api_tokens)
+        .await
+        .unwrap_or_default()
+        .into_iter()
+        .map(|k| ApiKeyView::new(k, now))
+        .collect();
+
+    let mut ctx = page_context(claims);
+    ctx.insert("keys", &keys);
+    ctx.insert("new_key", &new_key);
+
+    let rendered = tmpl
+        .render("api_keys.html", &ctx)
+        .unwrap_or_else(|e| format!("Template error: {e}"));
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(rendered)
+}
+
+#[derive(Deserialize)]
+pub struct ApiKeyForm {
+    name: String,
+    owner: String,
+    expires_at: Option<String>,
+    rpm_limit: Option<String>,
+    tpm_limit: Option<String>,
+    max_concurrent: Option<String>,
+}
+
+impl ApiKeyForm {
+    /// 空值或非正数表示不限制
+    fn limits(&self) -> KeyLimits {
+        let parse = |v: &Option<String>| v.as_deref().and_then(|v| v.trim().parse::<u32>().ok()).filter(|v| *v > 0);
+        KeyLimits {
+            rpm: parse(&self.rpm_limit),
+            tpm: parse(&self.tpm_limit),
+            max_concurrent: parse(&self.max_concurrent),
+        }
+    }
+}
+
+/// 将 `<input type="date">` 的值解析为当天结束时刻（UTC）；空值表示永不过期
+fn parse_expiry(value: Option<&str>) -> Option<NaiveDateTime> {
+    let date = NaiveDate::parse_from_str(value?.trim(), "%Y-%m-%d").ok()?;
+    date.and_hms_opt(23, 59, 59)
+}
+
+pub async fn generate_api_token(claims: web::ReqData<Claims>, form: web::Form<ApiKeyForm>, tmpl: web::Data<Tera>, pool: web::Data<DbPool>, settings: web::Data<Settings>) -> impl Responder {
+    let expires = parse_expiry(form.expires_at.as_deref());
+    let limits = form.limits();
+    let form = form.into_inner();
+    let secret = settings.auth.api_key_secret().to_string();
+    match db::run(&pool, move |pool| services::generate_api_token(pool, &secret, form.name.trim(), form.owner.trim(), expires, limits)).await {
+        Ok(token) => render_api_keys(&tmpl, &pool, &claims, Some(&token)).await,
+        Err(e) => HttpResponse::InternalServerError().body(format!("Error: {e}")),
+    }
+}
+
+pub async fn update_api_token(path: web::Path<i32>, form: web::Form<ApiKeyForm>, pool: web::Data<DbPool>) -> impl Responder {
+    let tid = path.into_inner();
+    let expires = parse_expiry(form.expires_at.as_deref());
+    let limits = form.limits();
+    let form = form.into_inner();
+    if let Err(e) = db::run(&pool, move |pool| services::update_api_token(pool, tid, form.name.trim(), form.owner.trim(), expires, limits)).await {
+        return HttpResponse::InternalServerError().body(format!("Error: {e}"));
+    }
+    HttpResponse::Found()
+        .append_header(("Location", "/admin/api_keys"))
+        .finish()
+}
+
+pub async fn revoke_api_token(path: web::Path<i32>, pool: web::Data<DbPool>) -> impl Responder {
+    let tid = path.into_inner();
+    let _ = db::run(&pool, move |pool| services::revoke_api_token(pool, tid)).await;
+    HttpResponse::Found()
+        .append_header(("Location", "/admin/api_keys"))
+        .finish()
+}
+
+#[derive(Deserialize)]
+pub struct ModelForm {
+    alias: String,
+    upstream_model: String,
+    owned_by: String,
+    context_window: Option<String>,
+}
+
+pub async fn add_model(form: web::Form<ModelForm>, pool: web::Data<DbPool>) -> impl Responder {
+    // 空字符串表示未知的上下文窗口
+    let context_window = form.context_window.as_deref().and_then(|v| v.trim().parse::<i32>().ok());
+    let form = form.into_inner();
+    if let Err(e) = db::run(&pool, move |pool| {
+        services::create_model(pool, form.alias.trim(), form.upstream_model.trim(), form.owned_by.trim(), context_window)
+    })
+    .await
+    {
+        return HttpResponse::InternalServerError().body(format!("Error: {e}"));
+    }
+    HttpResponse::Found()
+        .append_header(("Location", "/admin/credentials"))
+        .finish()
+}
+
+pub async fn delete_model(path: web::Path<i32>, pool: web::Data<DbPool>) -> impl Responder {
+    let mid = path.into_inner();
+    let _ = db::run(&pool, move |pool| services::remove_model(pool, mid)).await;
     HttpResponse::Found()
         .append_header(("Location", "/admin/credentials"))
         .finish()
 }
 
-fn check_cookie(req: &HttpRequest) -> bool {
-    if let Some(cookie) = req.cookie("admin_jwt") {
-        return auth::validate_token(cookie.value()).is_ok();
+/// 默认统计最近 7 天
+const DEFAULT_USAGE_DAYS: u64 = 7;
+/// 单次最多统计一年
+const MAX_USAGE_DAYS: u64 = 366;
+
+#[derive(Deserialize)]
+pub struct UsageQuery {
+    from: Option<String>,
+    to: Option<String>,
+}
+
+impl UsageQuery {
+    /// 解析 UTC 日期闭区间，缺省或非法时使用最近 7 天
+    fn range(&self) -> (NaiveDate, NaiveDate) {
+        let parse = |v: &Option<String>| v.as_deref().and_then(|v| NaiveDate::parse_from_str(v.trim(), "%Y-%m-%d").ok());
+        let to = parse(&self.to).unwrap_or_else(|| Utc::now().date_naive());
+        let from = parse(&self.from)
+            .filter(|from| *from <= to)
+            .unwrap_or_else(|| to - Days::new(DEFAULT_USAGE_DAYS - 1));
+        (from.max(to - Days::new(MAX_USAGE_DAYS - 1)), to)
+    }
+}
+
+/// 读取区间内的用量聚合行，以及解析名称所需的 API Key 与凭据列表
+async fn load_usage(pool: &DbPool, from: NaiveDate, to: NaiveDate) -> anyhow::Result<(Vec<UsageRow>, report::Labels)> {
+    let start = from.and_hms_opt(0, 0, 0).unwrap_or_default();
+    let end = (to + Days::new(1)).and_hms_opt(0, 0, 0).unwrap_or_default();
+    db::run(pool, move |pool| {
+        let rows = services::usage_rows(pool, start, end)?;
+        let labels = report::Labels::new(&services::list_api_tokens(pool)?, &services::list_credentials(pool)?);
+        Ok((rows, labels))
+    })
+    .await
+}
+
+/// 显示用量统计页面
+pub async fn show_usage(claims: web::ReqData<Claims>, query: web::Query<UsageQuery>, tmpl: web::Data<Tera>, pool: web::Data<DbPool>) -> impl Responder {
+    let (from, to) = query.range();
+    let (rows, labels) = match load_usage(&pool, from, to).await {
+        Ok(usage) => usage,
+        Err(e) => return HttpResponse::InternalServerError().body(format!("Error: {e}")),
+    };
+
+    let mut ctx = page_context(&claims);
+    ctx.insert("from", &from.to_string());
+    ctx.insert("to", &to.to_string());
+    ctx.insert("report", &report::UsageReport::build(&rows, &labels, from, to));
+
+    let rendered = tmpl
+        .render("usage.html", &ctx)
+        .unwrap_or_else(|e| format!("Template error: {e}"));
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(rendered)
+}
+
+/// 以 CSV 导出用量统计
+pub async fn export_usage(query: web::Query<UsageQuery>, pool: web::Data<DbPool>) -> impl Responder {
+    let (from, to) = query.range();
+    match load_usage(&pool, from, to).await {
+        Ok((rows, labels)) => HttpResponse::Ok()
+            .content_type("text/csv; charset=utf-8")
+            .append_header(("Content-Disposition", format!("attachment; filename=\"usage-{from}-{to}.csv\"")))
+            .body(report::to_csv(&rows, &labels)),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Error: {e}")),
+    }
+}
+
+/// 生成的初始密码长度，新用户首次登录后须修改
+const NEW_USER_PASSWORD_LEN: usize = 16;
+/// 用户管理页显示的最近登录锁定记录条数
+const RECENT_LOCKOUTS: i64 = 50;
+
+/// 显示用户列表（仅 owner）
+pub async fn show_users(claims: web::ReqData<Claims>, tmpl: web::Data<Tera>, pool: web::Data<DbPool>) -> impl Responder {
+    render_users(&tmpl, &pool, &claims, None, None).await
+}
+
+/// `created` 为刚创建的用户名与仅显示一次的初始密码
+async fn render_users(tmpl: &Tera, pool: &DbPool, claims: &Claims, created: Option<(&str, &str)>, error: Option<&str>) -> HttpResponse {
+    let users = db::run(pool, repository::list_users).await.unwrap_or_default();
+    let roles: Vec<&str> = Role::ALL.iter().map(|r| r.as_str()).collect();
+    let now = Utc::now().naive_utc();
+    let lockouts: Vec<_> = db::run(pool, |pool| repository::recent_lockouts(pool, RECENT_LOCKOUTS))
+        .await
+        .unwrap_or_default()
+        .into_iter()
+        .map(|l| serde_json::json!({ "active": l.is_active(now), "lockout": l }))
+        .collect();
+
+    let mut ctx = page_context(claims);
+    ctx.insert("users", &users);
+    ctx.insert("lockouts", &lockouts);
+    ctx.insert("roles", &roles);
+    ctx.insert("created", &created.map(|(username, password)| serde_json::json!({ "username": username, "password": password })));
+    ctx.insert("error", &error);
+
+    let rendered = tmpl
+        .render("users.html", &ctx)
+        .unwrap_or_else(|e| format!("Template error: {e}"));
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(rendered)
+}
+
+#[derive(Deserialize)]
+pub struct UserForm {
+    username: String,
+    role: String,
+    password: Option<String>,
+}
+
+/// 新建用户；未填写密码时生成随机密码。两种情况下新用户首次登录都须修改密码
+pub async fn create_user(claims: web::ReqData<Claims>, form: web::Form<UserForm>, tmpl: web::Data<Tera>, pool: web::Data<DbPool>) -> impl Responder {
+    let form = form.into_inner();
+    let username = form.username.trim().to_string();
+    if username.is_empty() {
+        return render_users(&tmpl, &pool, &claims, None, Some("用户名不能为空")).await;
     }
-    false
+    let Some(role) = Role::parse(&form.role) else {
+        return render_users(&tmpl, &pool, &claims, None, Some("未知的角色")).await;
+    };
+    let password = match form.password.filter(|p| !p.is_empty()) {
+        Some(password) => {
+            if let Err(reason) = utils::check_password_strength(&username, &password) {
+                return render_users(&tmpl, &pool, &claims, None, Some(reason)).await;
+            }
+            password
+        }
+        None => utils::generate_random_password(NEW_USER_PASSWORD_LEN),
+    };
+
+    let (name, pwd) = (username.clone(), password.clone());
+    let result = db::run(&pool, move |pool| {
+        if repository::get_user_by_username(pool, &name).is_ok() {
+            anyhow::bail!("用户 {name} 已存在");
+        }
+        repository::create_user(pool, &name, &utils::hash_password(&pwd)?, role, true)
+    })
+    .await;
+    match result {
+        Ok(_) => render_users(&tmpl, &pool, &claims, Some((&username, &password)), None).await,
+        Err(e) => render_users(&tmpl, &pool, &claims, None, Some(&e.to_string())).await,
+    }
+}
+
+#[derive(Deserialize)]
+pub struct RoleForm {
+    role: String,
+}
+
+pub async fn update_user_role(claims: web::ReqData<Claims>, path: web::Path<i32>, form: web::Form<RoleForm>, tmpl: web::Data<Tera>, pool: web::Data<DbPool>) -> impl Responder {
+    let Some(role) = Role::parse(&form.role) else {
+        return render_users(&tmpl, &pool, &claims, None, Some("未知的角色")).await;
+    };
+    let uid = path.into_inner();
+    let me = claims.sub.clone();
+    modify_user(&tmpl, &pool, &claims, move |pool| {
+        ensure_not_self(pool, uid, &me)?;
+        repository::set_user_role(pool, uid, role)
+    })
+    .await
+}
+
+pub async fn disable_user(claims: web::ReqData<Claims>, path: web::Path<i32>, tmpl: web::Data<Tera>, pool: web::Data<DbPool>) -> impl Responder {
+    let uid = path.into_inner();
+    let me = claims.sub.clone();
+    modify_user(&tmpl, &pool, &claims, move |pool| {
+        ensure_not_self(pool, uid, &me)?;
+        repository::set_user_disabled(pool, uid, true)
+    })
+    .await
+}
+
+/// 为丢失认证器与恢复码的用户关闭两步验证
+pub async fn reset_user_totp(claims: web::ReqData<Claims>, path: web::Path<i32>, tmpl: web::Data<Tera>, pool: web::Data<DbPool>) -> impl Responder {
+    let uid = path.into_inner();
+    modify_user(&tmpl, &pool, &claims, move |pool| repository::disable_totp(pool, uid)).await
+}
+
+pub async fn enable_user(claims: web::ReqData<Claims>, path: web::Path<i32>, tmpl: web::Data<Tera>, pool: web::Data<DbPool>) -> impl Responder {
+    let uid = path.into_inner();
+    modify_user(&tmpl, &pool, &claims, move |pool| repository::set_user_disabled(pool, uid, false)).await
+}
+
+pub async fn delete_user(claims: web::ReqData<Claims>, path: web::Path<i32>, tmpl: web::Data<Tera>, pool: web::Data<DbPool>) -> impl Responder {
+    let uid = path.into_inner();
+    let me = claims.sub.clone();
+    modify_user(&tmpl, &pool, &claims, move |pool| {
+        ensure_not_self(pool, uid, &me)?;
+        repository::delete_user(pool, uid)
+    })
+    .await
+}
+
+/// 手动解除登录锁定，同时清除对应用户名或 IP 的失败计数
+pub async fn unlock_login(claims: web::ReqData<Claims>, path: web::Path<i32>, tmpl: web::Data<Tera>, pool: web::Data<DbPool>, throttle: web::Data<LoginThrottle>) -> impl Responder {
+    let id = path.into_inner();
+    match db::run(&pool, move |pool| repository::clear_lockout(pool, id)).await {
+        Ok(Some(lockout)) => {
+            if let Some(scope) = Scope::parse(&lockout.scope) {
+                throttle.unlock(scope, &lockout.subject);
+            }
+            log::info!("user:{} cleared login lockout of {} {:?}", claims.sub, lockout.scope, lockout.subject);
+            HttpResponse::Found()
+                .append_header(("Location", "/admin/users"))
+                .finish()
+        }
+        Ok(None) => render_users(&tmpl, &pool, &claims, None, Some("锁定记录不存在")).await,
+        Err(e) => render_users(&tmpl, &pool, &claims, None, Some(&e.to_string())).await,
+    }
+}
+
+/// 执行用户修改，成功后回到用户列表，失败时在列表页显示原因
+async fn modify_user<F>(tmpl: &Tera, pool: &DbPool, claims: &Claims, f: F) -> HttpResponse
+where
+    F: FnOnce(&DbPool) -> anyhow::Result<()> + Send + 'static,
+{
+    match db::run(pool, f).await {
+        Ok(()) => HttpResponse::Found()
+            .append_header(("Location", "/admin/users"))
+            .finish(),
+        Err(e) => render_users(tmpl, pool, claims, None, Some(&e.to_string())).await,
+    }
+}
+
+/// 不能停用、删除自己或修改自己的角色，避免把自己锁在外面
+fn ensure_not_self(pool: &DbPool, uid: i32, me: &str) -> anyhow::Result<()> {
+    if repository::get_user(pool, uid)?.is_some_and(|u| u.username == me) {
+        anyhow::bail!("不能停用、删除自己或修改自己的角色");
+    }
+    Ok(())
+}
+
+/// 管理页面的公共模板变量：当前用户名与角色
+fn page_context(claims: &Claims) -> Context {
+    let mut ctx = Context::new();
+    ctx.insert("username", &claims.sub);
+    ctx.insert("role", claims.role.as_str());
+    ctx
+}
+
+/// 写入会话 Cookie 的跳转响应
+fn redirect_with_session(location: &str, token: String, settings: &Settings) -> HttpResponse {
+    HttpResponse::Found()
+        .append_header(("Location", location))
+        .cookie(admin_cookie("admin_jwt", token, "/", settings))
+        .finish()
 }
 
-fn render_error(tmpl: &Tera, msg: &str) -> HttpResponse {
+pub(crate) fn render_error(tmpl: &Tera, msg: &str) -> HttpResponse {
     let mut ctx = Context::new();
     ctx.insert("error", msg);
     let rendered = tmpl
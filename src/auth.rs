@@ -1,61 +1,414 @@
-use actix_web::{HttpResponse, Responder, web};
-use anyhow::Result;
+use actix_web::{HttpRequest, HttpResponse, Responder, web};
+use actix_web_httpauth::extractors::bearer::BearerAuth;
+use anyhow::{anyhow, bail, Result};
 use chrono::{Duration, Utc};
-use jsonwebtoken::{decode, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use jsonwebtoken::{decode, decode_header, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
-use std::env;
-use crate::{repository, utils};
+use std::fmt;
+use std::sync::LazyLock;
+use crate::crypto::MasterKey;
+use crate::login_throttle::{LoginAttempt, LoginThrottle};
+use crate::{metrics, totp, db::{self, DbPool}, models::{KeyLimits, NewLoginLockout, Role, User}, repository, settings::{AuthSettings, INSECURE_JWT_SECRET}, utils};
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Claims {
     pub sub: String,
     pub exp: usize,
+    /// 签发时的角色；缺省（旧 Token）按只读处理，管理后台以数据库中的当前角色为准
+    #[serde(default)]
+    pub role: Role,
+    /// 须先修改密码，此时只能访问修改密码的页面与接口
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub must_change_password: bool,
+    /// 密码已通过、尚待 TOTP 验证的临时 Token，只能用于完成第二步登录
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub mfa_pending: bool,
+    /// 签发时用户的 [`User::token_version`]，修改密码后旧 Token 不再被接受
+    #[serde(default)]
+    pub token_version: i32,
+}
+
+/// 已认证的调用方，由认证中间件写入请求扩展
+#[derive(Debug, Clone)]
+pub enum Principal {
+    /// 通过 `api_tokens` 中的 API Key 认证
+    ApiKey { id: i32, name: String, limits: KeyLimits },
+    /// 通过管理员 JWT 认证
+    User { username: String },
+}
+
+impl fmt::Display for Principal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Principal::ApiKey { id, name, .. } => write!(f, "api_key:{id}({name})"),
+            Principal::User { username } => write!(f, "user:{username}"),
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct AuthRequest {
     username: String,
     password: String,
+    /// 已启用两步验证时必填：TOTP 验证码或恢复码
+    totp_code: Option<String>,
 }
 
-pub fn validate_token(token: &str) -> Result<Claims> {
-    let secret = env::var("JWT_SECRET").unwrap_or_else(|_| "secret".into());
-    let decoding_key = DecodingKey::from_secret(secret.as_bytes());
-    let validation = Validation::new(Algorithm::HS256);
-    
-    let token_data = decode::<Claims>(token, &decoding_key, &validation)?;
-    Ok(token_data.claims)
+/// 旧版单一密钥 `auth.jwt_secret` 对应的 `kid`
+const DEFAULT_KID: &str = "default";
+
+struct JwtKey {
+    kid: String,
+    encoding: EncodingKey,
+    decoding: DecodingKey,
 }
 
-pub fn generate_token(user_id: &str) -> Result<String> {
-    let claims = Claims {
-        sub: user_id.to_owned(),
-        exp: (Utc::now() + Duration::hours(24)).timestamp() as usize,
+/// JWT 签名密钥集合：当前密钥签发新 Token，其余密钥仅用于校验，以便轮换时不必让所有人重新登录
+pub struct JwtKeys {
+    /// 第一个为当前签发密钥
+    keys: Vec<JwtKey>,
+}
+
+impl JwtKeys {
+    /// 由 `auth.jwt_secret`（`kid` 为 `default`）与 `auth.jwt_keys` 构造；
+    /// 签发密钥为 `auth.jwt_active_kid`，只配置了一个新密钥时可省略
+    pub fn from_settings(auth: &AuthSettings) -> Result<Self> {
+        let mut secrets: Vec<(&str, &str)> = auth.jwt_keys.iter().map(|(kid, s)| (kid.as_str(), s.as_str())).collect();
+        secrets.sort();
+        if let Some(secret) = &auth.jwt_secret {
+            if auth.jwt_keys.contains_key(DEFAULT_KID) {
+                bail!("auth.jwt_keys.{DEFAULT_KID} conflicts with auth.jwt_secret");
+            }
+            secrets.push((DEFAULT_KID, secret));
+        }
+        if secrets.is_empty() {
+            // 仅开发模式下会走到这里，见 `AuthSettings::check_secrets`
+            secrets.push((DEFAULT_KID, INSECURE_JWT_SECRET));
+        }
+
+        let active = match (&auth.jwt_active_kid, auth.jwt_keys.len()) {
+            (Some(kid), _) => kid.as_str(),
+            (None, 0) => DEFAULT_KID,
+            (None, 1) => auth.jwt_keys.keys().next().map(String::as_str).unwrap_or(DEFAULT_KID),
+            (None, _) => bail!("auth.jwt_active_kid must be set when auth.jwt_keys has more than one key"),
+        };
+        let Some(pos) = secrets.iter().position(|(kid, _)| *kid == active) else {
+            bail!("auth.jwt_active_kid {active:?} does not match any configured JWT key");
+        };
+        secrets.swap(0, pos);
+
+        let keys = secrets
+            .into_iter()
+            .map(|(kid, secret)| JwtKey {
+                kid: kid.to_string(),
+                encoding: EncodingKey::from_secret(secret.as_bytes()),
+                decoding: DecodingKey::from_secret(secret.as_bytes()),
+            })
+            .collect();
+        Ok(Self { keys })
+    }
+
+    /// 当前签发密钥的 `kid`
+    pub fn active_kid(&self) -> &str {
+        &self.keys[0].kid
+    }
+
+    /// 校验会话 Token；尚待 TOTP 验证的临时 Token 不被接受
+    pub fn validate_token(&self, token: &str) -> Result<Claims> {
+        let claims = self.decode_claims(token)?;
+        if claims.mfa_pending {
+            bail!("Two-factor authentication is pending");
+        }
+        Ok(claims)
+    }
+
+    /// 校验第二步登录所用的临时 Token
+    pub fn validate_pending_token(&self, token: &str) -> Result<Claims> {
+        let claims = self.decode_claims(token)?;
+        if !claims.mfa_pending {
+            bail!("Not a pending two-factor token");
+        }
+        Ok(claims)
+    }
+
+    /// 按 `kid` 选择校验密钥；不带 `kid` 的旧 Token 依次尝试所有密钥
+    fn decode_claims(&self, token: &str) -> Result<Claims> {
+        let validation = Validation::new(Algorithm::HS256);
+        match decode_header(token)?.kid {
+            Some(kid) => {
+                let key = self
+                    .keys
+                    .iter()
+                    .find(|k| k.kid == kid)
+                    .ok_or_else(|| anyhow!("Unknown JWT key id {kid:?}"))?;
+                Ok(decode::<Claims>(token, &key.decoding, &validation)?.claims)
+            }
+            None => self
+                .keys
+                .iter()
+                .find_map(|k| decode::<Claims>(token, &k.decoding, &validation).ok())
+                .map(|data| data.claims)
+                .ok_or_else(|| anyhow!("Invalid token")),
+        }
+    }
+
+    /// 以当前密钥签发 24 小时有效的 Token，头部带 `kid`
+    pub fn generate_token(&self, user: &User) -> Result<String> {
+        self.sign(user, Duration::hours(24), false)
+    }
+
+    /// 密码通过后签发 5 分钟有效的临时 Token，用于完成 TOTP 验证
+    pub fn generate_pending_token(&self, user: &User) -> Result<String> {
+        self.sign(user, Duration::minutes(5), true)
+    }
+
+    fn sign(&self, user: &User, ttl: Duration, mfa_pending: bool) -> Result<String> {
+        let claims = Claims {
+            sub: user.username.clone(),
+            exp: (Utc::now() + ttl).timestamp() as usize,
+            role: user.role(),
+            must_change_password: user.must_change_password,
+            mfa_pending,
+            token_version: user.token_version,
+        };
+        let key = &self.keys[0];
+        let header = Header { kid: Some(key.kid.clone()), ..Header::default() };
+        Ok(encode(&header, &claims, &key.encoding)?)
+    }
+}
+
+pub async fn auth_handler(
+    http_req: HttpRequest,
+    req: web::Json<AuthRequest>,
+    pool: web::Data<DbPool>,
+    jwt_keys: web::Data<JwtKeys>,
+    master_key: web::Data<MasterKey>,
+    throttle: web::Data<LoginThrottle>,
+) -> impl Responder {
+    let attempt = match throttle.begin(&throttle.client_ip(&http_req), &req.username) {
+        Ok(attempt) => attempt,
+        Err(wait) => return too_many_attempts(wait),
     };
-    
-    let secret = env::var("JWT_SECRET").unwrap_or_else(|_| "secret".into());
-    let token = encode(
-        &Header::default(),
-        &claims,
-        &EncodingKey::from_secret(secret.as_bytes()),
-    )?;
-    
-    Ok(token)
-}
-
-pub async fn auth_handler(req: web::Json<AuthRequest>) -> impl Responder {
-    match repository::get_user_by_username(&req.username) {
-        Ok(user) => {
-            if utils::verify_password(&user.password_hash, &req.password).unwrap_or(false) {
-                match generate_token(&user.username) {
-                    Ok(token) => HttpResponse::Ok().json(json!({ "token": token })),
-                    Err(_) => HttpResponse::InternalServerError().finish(),
-                }
-            } else {
-                HttpResponse::Unauthorized().finish()
+
+    let (username, password) = (req.username.clone(), req.password.clone());
+    let user = match db::run(&pool, move |pool| authenticate(pool, &username, &password)).await {
+        Ok(Some(user)) => user,
+        Ok(None) => {
+            record_login_failure(&pool, attempt, "password").await;
+            return HttpResponse::Unauthorized().finish();
+        }
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    };
+    if user.totp_enabled() {
+        let Some(code) = req.totp_code.clone() else {
+            return HttpResponse::Unauthorized().json(json!({ "error": "totp_required" }));
+        };
+        let master_key = master_key.into_inner();
+        let uid = user.id;
+        let verified = db::run(&pool, move |pool| {
+            let user = repository::get_user(pool, uid)?.ok_or_else(|| anyhow!("User disappeared"))?;
+            verify_second_factor(pool, &master_key, &user, &code)
+        })
+        .await;
+        if !verified.unwrap_or(false) {
+            record_login_failure(&pool, attempt, "totp").await;
+            return HttpResponse::Unauthorized().json(json!({ "error": "invalid_totp_code" }));
+        }
+    }
+    attempt.succeeded();
+    match jwt_keys.generate_token(&user) {
+        Ok(token) => HttpResponse::Ok().json(json!({ "token": token, "must_change_password": user.must_change_password })),
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+/// 用户不存在时用于比对的哈希，使未知用户名与错误密码的耗时一致
+static DUMMY_PASSWORD_HASH: LazyLock<String> =
+    LazyLock::new(|| utils::hash_password(&utils::generate_random_password(16)).expect("hashing a random password"));
+
+/// 校验用户名与密码，用户不存在、已停用或密码错误时一律返回 None。
+/// 无论哪种情况都会执行一次 Argon2，需在 [`db::run`] 中调用
+pub fn authenticate(pool: &DbPool, username: &str, password: &str) -> Result<Option<User>> {
+    let user = repository::get_user_by_username(pool, username).ok();
+    let hash = user.as_ref().map_or(DUMMY_PASSWORD_HASH.as_str(), |u| u.password_hash.as_str());
+    let matches = utils::verify_password(hash, password).unwrap_or(false);
+    Ok(user.filter(|u| matches && u.is_active()))
+}
+
+/// 登录尝试过于频繁（退避或锁定中）时页面上的提示
+pub fn too_many_attempts_message(wait: std::time::Duration) -> String {
+    format!("尝试次数过多，请 {} 秒后再试", retry_after_secs(wait))
+}
+
+/// 登录尝试过于频繁（退避或锁定中）时 JSON 接口的响应
+pub fn too_many_attempts(wait: std::time::Duration) -> HttpResponse {
+    HttpResponse::TooManyRequests()
+        .append_header(("Retry-After", retry_after_secs(wait).to_string()))
+        .json(json!({ "error": "too_many_attempts" }))
+}
+
+/// 记录一次登录失败；触发锁定时写入锁定记录供管理员查看。`stage` 为 `password` 或 `totp`
+pub async fn record_login_failure(pool: &DbPool, attempt: LoginAttempt<'_>, stage: &'static str) {
+    metrics::LOGIN_FAILURES.with_label_values(&[stage]).inc();
+    let ip = attempt.ip().to_string();
+    for lockout in attempt.failed() {
+        metrics::LOGIN_LOCKOUTS.with_label_values(&[lockout.scope.as_str()]).inc();
+        log::warn!(
+            "Locked out {} {:?} for {}s after {} failed logins (last attempt from {})",
+            lockout.scope.as_str(),
+            lockout.subject,
+            lockout.duration.as_secs(),
+            lockout.failures,
+            ip
+        );
+        let now = Utc::now().naive_utc();
+        let locked_until = now + Duration::seconds(lockout.duration.as_secs() as i64);
+        let ip = ip.clone();
+        let result = db::run(pool, move |pool| {
+            repository::record_lockout(
+                pool,
+                &NewLoginLockout {
+                    created_at: now,
+                    scope: lockout.scope.as_str(),
+                    subject: &lockout.subject,
+                    ip: &ip,
+                    failures: lockout.failures as i32,
+                    locked_until,
+                },
+            )
+        })
+        .await;
+        if let Err(e) = result {
+            log::error!("Failed to record login lockout: {}", e);
+        }
+    }
+}
+
+/// `Retry-After` 的秒数，向上取整
+pub fn retry_after_secs(wait: std::time::Duration) -> u64 {
+    wait.as_secs() + u64::from(wait.subsec_nanos() > 0)
+}
+
+/// 校验第二步验证：纯数字按 TOTP 验证码处理（拒绝重放），否则按一次性恢复码处理。
+/// 会执行 Argon2 与数据库写入，需在 [`db::run`] 中调用
+pub fn verify_second_factor(pool: &DbPool, master_key: &MasterKey, user: &User, code: &str) -> Result<bool> {
+    let Some(stored) = user.totp_secret.as_deref().filter(|_| user.totp_enabled()) else {
+        return Ok(false);
+    };
+    if totp::is_totp_code(code) {
+        let secret = master_key.decrypt(stored)?;
+        let now = Utc::now().timestamp().max(0) as u64;
+        let last_step = user.totp_last_step.map(|s| s.max(0) as u64);
+        return match totp::verify(&secret, code, now, last_step) {
+            Some(step) => repository::record_totp_step(pool, user.id, step as i64),
+            None => Ok(false),
+        };
+    }
+
+    let code = totp::normalize_recovery_code(code);
+    for recovery in repository::unused_recovery_codes(pool, user.id)? {
+        if utils::verify_password(&recovery.code_hash, &code).unwrap_or(false) {
+            let used = repository::use_recovery_code(pool, recovery.id)?;
+            if used {
+                log::warn!("user:{} signed in with a recovery code", user.username);
             }
+            return Ok(used);
+        }
+    }
+    Ok(false)
+}
+
+/// 修改密码失败的原因
+pub enum PasswordChangeError {
+    /// 原密码错误或用户不存在
+    WrongPassword,
+    /// 新密码不满足强度策略
+    Weak(&'static str),
+    /// 原密码错误次数过多，需等待后重试
+    TooManyAttempts(std::time::Duration),
+    Internal(anyhow::Error),
+}
+
+impl PasswordChangeError {
+    pub fn message(&self) -> String {
+        match self {
+            PasswordChangeError::WrongPassword => "原密码错误".into(),
+            PasswordChangeError::Weak(reason) => (*reason).into(),
+            PasswordChangeError::TooManyAttempts(wait) => too_many_attempts_message(*wait),
+            PasswordChangeError::Internal(e) => format!("Error: {e}"),
+        }
+    }
+}
+
+/// 校验原密码与新密码强度后修改密码，并清除 `must_change_password`；返回更新后的用户。
+/// 原密码校验与登录共用失败计数，被盗的会话无法借此无限猜测密码
+pub async fn change_password(
+    pool: &DbPool,
+    throttle: &LoginThrottle,
+    ip: &str,
+    username: String,
+    old_password: String,
+    new_password: String,
+) -> Result<User, PasswordChangeError> {
+    if old_password == new_password {
+        return Err(PasswordChangeError::Weak("新密码不能与原密码相同"));
+    }
+    utils::check_password_strength(&username, &new_password).map_err(PasswordChangeError::Weak)?;
+    let attempt = throttle.begin(ip, &username).map_err(PasswordChangeError::TooManyAttempts)?;
+
+    let result = db::run(pool, move |pool| {
+        let Some(user) = repository::get_user_by_username(pool, &username).ok().filter(User::is_active) else {
+            return Ok(None);
+        };
+        if !utils::verify_password(&user.password_hash, &old_password).unwrap_or(false) {
+            return Ok(None);
+        }
+        repository::set_password(pool, &username, &utils::hash_password(&new_password)?, false)?;
+        Ok(Some(repository::get_user_by_username(pool, &username)?))
+    })
+    .await;
+    match result {
+        Ok(Some(user)) => {
+            attempt.succeeded();
+            Ok(user)
         }
-        Err(_) => HttpResponse::Unauthorized().finish(),
+        Ok(None) => {
+            record_login_failure(pool, attempt, "password").await;
+            Err(PasswordChangeError::WrongPassword)
+        }
+        Err(e) => Err(PasswordChangeError::Internal(e)),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ChangePasswordRequest {
+    old_password: String,
+    new_password: String,
+}
+
+/// `POST /api/auth/password`：以 Bearer JWT 认证修改自己的密码，成功后返回新 Token
+pub async fn change_password_handler(
+    http_req: HttpRequest,
+    credentials: BearerAuth,
+    req: web::Json<ChangePasswordRequest>,
+    pool: web::Data<DbPool>,
+    jwt_keys: web::Data<JwtKeys>,
+    throttle: web::Data<LoginThrottle>,
+) -> impl Responder {
+    let Ok(claims) = jwt_keys.validate_token(credentials.token()) else {
+        return HttpResponse::Unauthorized().finish();
+    };
+    let req = req.into_inner();
+    let ip = throttle.client_ip(&http_req);
+    match change_password(&pool, &throttle, &ip, claims.sub, req.old_password, req.new_password).await {
+        Ok(user) => match jwt_keys.generate_token(&user) {
+            Ok(token) => HttpResponse::Ok().json(json!({ "token": token })),
+            Err(_) => HttpResponse::InternalServerError().finish(),
+        },
+        Err(PasswordChangeError::WrongPassword) => HttpResponse::Unauthorized().json(json!({ "error": PasswordChangeError::WrongPassword.message() })),
+        Err(e @ PasswordChangeError::Weak(_)) => HttpResponse::BadRequest().json(json!({ "error": e.message() })),
+        Err(PasswordChangeError::TooManyAttempts(wait)) => too_many_attempts(wait),
+        Err(PasswordChangeError::Internal(_)) => HttpResponse::InternalServerError().finish(),
     }
 }
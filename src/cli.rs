@@ -0,0 +1,335 @@
+//! 服务器二进制的运维子命令，供容器内的脚本非交互地调用
+
+use std::io::{BufRead, Read};
+use std::path::PathBuf;
+
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::{NaiveDate, Utc};
+use clap::{Args, Subcommand};
+use serde::Deserialize;
+
+use crate::crypto::MasterKey;
+use crate::db::{self, DbPool};
+use crate::models::{KeyLimits, Role};
+use crate::settings::Settings;
+use crate::{repository, services, utils};
+
+/// 未指定 `--password` 时生成的随机密码长度；生成的密码须在首次登录后修改
+const GENERATED_PASSWORD_LEN: usize = 16;
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// 启动 HTTP 服务（未指定子命令时的默认行为）
+    Serve,
+    /// 管理员账号
+    #[command(subcommand)]
+    Admin(AdminCommand),
+    /// 上游 Atlassian 凭据
+    #[command(subcommand)]
+    Credential(CredentialCommand),
+    /// OpenAI 兼容接口的 API Key
+    #[command(subcommand)]
+    Apikey(ApiKeyCommand),
+    /// 数据库维护
+    #[command(subcommand)]
+    Db(DbCommand),
+    /// 用 `auth.new_master_key`（CREDENTIAL_MASTER_KEY_NEW）重新加密所有凭据与两步验证密钥后退出
+    RotateMasterKey,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum AdminCommand {
+    /// 重置用户密码，未指定密码时生成随机密码并输出
+    ResetPassword {
+        #[arg(long, default_value = "admin")]
+        username: String,
+        #[command(flatten)]
+        password: PasswordArgs,
+    },
+    /// 新建管理员用户，未指定密码时生成随机密码并输出
+    CreateUser {
+        #[arg(long)]
+        username: String,
+        /// owner、operator 或 viewer
+        #[arg(long, default_value = "operator", value_parser = parse_role)]
+        role: Role,
+        #[command(flatten)]
+        password: PasswordArgs,
+    },
+    /// 为丢失认证器与恢复码的用户关闭两步验证
+    ResetTotp {
+        #[arg(long)]
+        username: String,
+    },
+}
+
+fn parse_role(value: &str) -> Result<Role, String> {
+    Role::parse(value).ok_or_else(|| format!("unknown role {value:?}, expected owner, operator or viewer"))
+}
+
+#[derive(Args, Debug)]
+pub struct PasswordArgs {
+    /// 新密码（会出现在进程列表中，建议使用 --password-stdin）
+    #[arg(long, conflicts_with = "password_stdin")]
+    password: Option<String>,
+    /// 从标准输入读取一行作为新密码
+    #[arg(long)]
+    password_stdin: bool,
+}
+
+impl PasswordArgs {
+    /// 返回密码以及是否为生成的密码；指定的密码须满足强度策略
+    fn resolve(&self, username: &str) -> Result<(String, bool)> {
+        if let Some(password) = &self.password {
+            utils::check_password_strength(username, password).map_err(|e| anyhow!(e))?;
+            return Ok((password.clone(), false));
+        }
+        if self.password_stdin {
+            let mut line = String::new();
+            std::io::stdin().lock().read_line(&mut line)?;
+            let password = line.trim_end_matches(['\r', '\n']).to_string();
+            if password.is_empty() {
+                bail!("No password given on stdin");
+            }
+            utils::check_password_strength(username, &password).map_err(|e| anyhow!(e))?;
+            return Ok((password, false));
+        }
+        Ok((utils::generate_random_password(GENERATED_PASSWORD_LEN), true))
+    }
+}
+
+#[derive(Subcommand, Debug)]
+pub enum CredentialCommand {
+    /// 新增凭据
+    Add {
+        #[arg(long)]
+        email: String,
+        #[arg(long)]
+        token: String,
+        #[arg(long, default_value_t = 1)]
+        weight: i32,
+    },
+    /// 列出凭据（不含 Token）
+    List,
+    /// 删除凭据
+    Remove { id: i32 },
+    /// 从 JSON 文件批量导入，格式为 `[{"email": "...", "token": "...", "weight": 1}]`；`-` 表示标准输入
+    Import { file: PathBuf },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ApiKeyCommand {
+    /// 生成新的 API Key，明文仅输出这一次
+    Create {
+        #[arg(long)]
+        name: String,
+        #[arg(long, default_value = "")]
+        owner: String,
+        /// 过期日期（UTC，当天结束时失效），如 2026-12-31
+        #[arg(long)]
+        expires_at: Option<NaiveDate>,
+        /// 每分钟请求数上限
+        #[arg(long)]
+        rpm: Option<u32>,
+        /// 每分钟 Token 数上限
+        #[arg(long)]
+        tpm: Option<u32>,
+        /// 并发请求上限
+        #[arg(long)]
+        max_concurrent: Option<u32>,
+    },
+    /// 吊销 API Key
+    Revoke { id: i32 },
+    /// 列出 API Key
+    List,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum DbCommand {
+    /// 执行尚未应用的迁移
+    Migrate,
+    /// 将数据库一致地备份到指定文件（文件不能已存在）
+    Backup { path: PathBuf },
+}
+
+/// 执行除 `serve` 以外的子命令
+pub fn run(command: Command, settings: &Settings) -> Result<()> {
+    match command {
+        Command::Serve => unreachable!("serve is handled by main"),
+        Command::Admin(command) => run_admin(command, &db::init_pool(&settings.database)),
+        Command::Credential(command) => {
+            let pool = db::init_pool(&settings.database);
+            let master_key = MasterKey::from_settings(&settings.auth)?;
+            run_credential(command, &pool, &master_key)
+        }
+        Command::Apikey(command) => run_api_key(command, &db::init_pool(&settings.database), settings),
+        Command::Db(command) => run_db(command, settings),
+        Command::RotateMasterKey => rotate_master_key(settings),
+    }
+}
+
+fn run_admin(command: AdminCommand, pool: &DbPool) -> Result<()> {
+    match command {
+        AdminCommand::ResetPassword { username, password } => {
+            let (password, generated) = password.resolve(&username)?;
+            repository::set_password(pool, &username, &utils::hash_password(&password)?, generated)?;
+            eprintln!("🔐 已重置 {username} 的密码");
+            if generated {
+                println!("{password}");
+            }
+        }
+        AdminCommand::CreateUser { username, role, password } => {
+            let (password, generated) = password.resolve(&username)?;
+            if repository::get_user_by_username(pool, &username).is_ok() {
+                bail!("User {username} already exists");
+            }
+            repository::create_user(pool, &username, &utils::hash_password(&password)?, role, generated)?;
+            eprintln!("👤 已创建用户 {username}（{}）", role.as_str());
+            if generated {
+                println!("{password}");
+            }
+        }
+        AdminCommand::ResetTotp { username } => {
+            let user = repository::get_user_by_username(pool, &username).with_context(|| format!("User {username} does not exist"))?;
+            repository::disable_totp(pool, user.id)?;
+            eprintln!("已关闭 {username} 的两步验证");
+        }
+    }
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct ImportedCredential {
+    email: String,
+    token: String,
+    #[serde(default = "default_weight")]
+    weight: i32,
+}
+
+fn default_weight() -> i32 {
+    1
+}
+
+fn run_credential(command: CredentialCommand, pool: &DbPool, master_key: &MasterKey) -> Result<()> {
+    match command {
+        CredentialCommand::Add { email, token, weight } => {
+            services::create_credential(pool, master_key, &email, &token, weight)?;
+            eprintln!("已添加凭据 {email}");
+        }
+        CredentialCommand::List => {
+            let now = Utc::now().naive_utc();
+            println!("id\temail\tweight\tstate\tfailures\tlast_error");
+            for c in services::list_credentials(pool)? {
+                println!(
+                    "{}\t{}\t{}\t{}\t{}\t{}",
+                    c.id,
+                    c.email,
+                    c.weight,
+                    c.state(now),
+                    c.failure_count,
+                    c.last_error.as_deref().unwrap_or("")
+                );
+            }
+        }
+        CredentialCommand::Remove { id } => {
+            if services::get_credential(pool, id)?.is_none() {
+                bail!("Credential {id} does not exist");
+            }
+            services::remove_credential(pool, id)?;
+            eprintln!("已删除凭据 {id}");
+        }
+        CredentialCommand::Import { file } => {
+            let mut raw = String::new();
+            if file.as_os_str() == "-" {
+                std::io::stdin().read_to_string(&mut raw)?;
+            } else {
+                raw = std::fs::read_to_string(&file).with_context(|| format!("Failed to read {}", file.display()))?;
+            }
+            let entries: Vec<ImportedCredential> = serde_json::from_str(&raw).context("Invalid credential file")?;
+            for entry in &entries {
+                services::create_credential(pool, master_key, &entry.email, &entry.token, entry.weight)?;
+            }
+            eprintln!("已导入 {} 个凭据", entries.len());
+        }
+    }
+    Ok(())
+}
+
+fn run_api_key(command: ApiKeyCommand, pool: &DbPool, settings: &Settings) -> Result<()> {
+    match command {
+        ApiKeyCommand::Create { name, owner, expires_at, rpm, tpm, max_concurrent } => {
+            let expires = expires_at.and_then(|d| d.and_hms_opt(23, 59, 59));
+            let limits = KeyLimits {
+                rpm: rpm.filter(|v| *v > 0),
+                tpm: tpm.filter(|v| *v > 0),
+                max_concurrent: max_concurrent.filter(|v| *v > 0),
+            };
+            let key = services::generate_api_token(pool, settings.auth.api_key_secret(), &name, &owner, expires, limits)?;
+            eprintln!("🔑 已生成 API Key {name}，明文仅显示这一次");
+            println!("{key}");
+        }
+        ApiKeyCommand::Revoke { id } => {
+            services::revoke_api_token(pool, id)?;
+            eprintln!("已吊销 API Key {id}");
+        }
+        ApiKeyCommand::List => {
+            let now = Utc::now().naive_utc();
+            println!("id\tprefix\tname\towner\tstate\texpires_at\tlast_used_at");
+            for k in services::list_api_tokens(pool)? {
+                let time = |t: Option<chrono::NaiveDateTime>| t.map(|t| t.to_string()).unwrap_or_default();
+                println!(
+                    "{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                    k.id,
+                    k.token_prefix,
+                    k.name,
+                    k.owner,
+                    k.state(now),
+                    time(k.expires_at),
+                    time(k.last_used_at)
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+fn run_db(command: DbCommand, settings: &Settings) -> Result<()> {
+    let pool = db::build_pool(&settings.database);
+    match command {
+        DbCommand::Migrate => {
+            let mut conn = pool.get()?;
+            let applied = db::run_migrations(&mut conn)?;
+            if applied.is_empty() {
+                eprintln!("数据库已是最新");
+            }
+            for version in applied {
+                println!("{version}");
+            }
+        }
+        DbCommand::Backup { path } => {
+            if path.exists() {
+                bail!("{} already exists", path.display());
+            }
+            let target = path.to_str().context("Backup path is not valid UTF-8")?;
+            db::backup(&pool, target)?;
+            eprintln!("💾 已备份到 {}", path.display());
+        }
+    }
+    Ok(())
+}
+
+/// 用新主密钥重新包裹所有凭据的数据密钥
+fn rotate_master_key(settings: &Settings) -> Result<()> {
+    let pool = db::init_pool(&settings.database);
+    let master_key = MasterKey::from_settings(&settings.auth)?;
+    services::encrypt_plaintext_credentials(&pool, &master_key)?;
+    let new_key = MasterKey::new_from_settings(&settings.auth)?;
+    let (credentials, totp_secrets) = services::rotate_master_key(&pool, &master_key, &new_key).context("Master key rotation failed")?;
+    println!(
+        "🔑 已用新主密钥 {} 重新加密 {} 个凭据与 {} 个两步验证密钥，请将 CREDENTIAL_MASTER_KEY 更新为新密钥",
+        new_key.id(),
+        credentials,
+        totp_secrets
+    );
+    Ok(())
+}
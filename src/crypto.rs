@@ -0,0 +1,232 @@
+//! 凭据 Token 的信封加密：每条记录使用随机数据密钥（DEK）以 AES-256-GCM 加密，
+//! DEK 再由主密钥（KEK）加密后与密文一同保存。轮换主密钥时只需重新包裹 DEK。
+
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use anyhow::{anyhow, bail, Context, Result};
+use base64::{engine::general_purpose::STANDARD as B64, Engine};
+use sha2::{Digest, Sha256};
+
+use crate::settings::AuthSettings;
+
+/// 密文格式：`enc:v1:<主密钥 ID>:<base64(nonce || 包裹后的 DEK)>:<base64(nonce || 密文)>`
+const ENVELOPE_PREFIX: &str = "enc:v1:";
+const NONCE_LEN: usize = 12;
+
+/// 主密钥（KEK）
+#[derive(Clone)]
+pub struct MasterKey {
+    id: String,
+    cipher: Aes256Gcm,
+}
+
+impl MasterKey {
+    /// 由 base64 编码的 32 字节密钥构造
+    pub fn from_base64(encoded: &str) -> Result<Self> {
+        let bytes = B64.decode(encoded.trim()).context("Master key is not valid base64")?;
+        if bytes.len() != 32 {
+            bail!("Master key must be 32 bytes, got {}", bytes.len());
+        }
+        let id = hex::encode(&Sha256::digest(&bytes)[..4]);
+        let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&bytes));
+        Ok(Self { id, cipher })
+    }
+
+    /// 当前主密钥：配置中的 `auth.master_key`，或 `auth.master_key_file` 指向的文件
+    pub fn from_settings(auth: &AuthSettings) -> Result<Self> {
+        Self::from_value_or_file(auth.master_key.as_deref(), auth.master_key_file.as_deref())?.ok_or_else(|| {
+            anyhow!("auth.master_key or auth.master_key_file must be set, e.g. via CREDENTIAL_MASTER_KEY (generate one with `openssl rand -base64 32`)")
+        })
+    }
+
+    /// 轮换的目标主密钥：`auth.new_master_key`，或 `auth.new_master_key_file` 指向的文件
+    pub fn new_from_settings(auth: &AuthSettings) -> Result<Self> {
+        Self::from_value_or_file(auth.new_master_key.as_deref(), auth.new_master_key_file.as_deref())?.ok_or_else(|| {
+            anyhow!("auth.new_master_key or auth.new_master_key_file must be set, e.g. via CREDENTIAL_MASTER_KEY_NEW (generate one with `openssl rand -base64 32`)")
+        })
+    }
+
+    fn from_value_or_file(value: Option<&str>, path: Option<&str>) -> Result<Option<Self>> {
+        if let Some(value) = value {
+            return Self::from_base64(value).context("Invalid master key").map(Some);
+        }
+        if let Some(path) = path {
+            let value = std::fs::read_to_string(path).with_context(|| format!("Failed to read master key file {path}"))?;
+            return Self::from_base64(&value).with_context(|| format!("Invalid key in {path}")).map(Some);
+        }
+        Ok(None)
+    }
+
+    /// 主密钥标识（密钥 SHA-256 的前 4 字节），用于识别密文由哪个主密钥加密
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// 加密明文 Token
+    pub fn encrypt(&self, plaintext: &str) -> Result<String> {
+        let dek = Aes256Gcm::generate_key(&mut OsRng);
+        let data_nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = Aes256Gcm::new(&dek)
+            .encrypt(&data_nonce, plaintext.as_bytes())
+            .map_err(|_| anyhow!("Failed to encrypt token"))?;
+        let wrapped = self.wrap(dek.as_slice())?;
+        Ok(format!("{ENVELOPE_PREFIX}{}:{}:{}", self.id, wrapped, join(&data_nonce, &ciphertext)))
+    }
+
+    /// 解密由 [`MasterKey::encrypt`] 生成的密文
+    pub fn decrypt(&self, stored: &str) -> Result<String> {
+        let envelope = Envelope::parse(stored)?;
+        let dek = self.unwrap(&envelope)?;
+        let (nonce, ciphertext) = split(envelope.data)?;
+        let plaintext = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&dek))
+            .decrypt(Nonce::from_slice(&nonce), ciphertext.as_slice())
+            .map_err(|_| anyhow!("Failed to decrypt token"))?;
+        String::from_utf8(plaintext).context("Decrypted token is not UTF-8")
+    }
+
+    /// 用新主密钥重新包裹 DEK，数据密文保持不变
+    pub fn rewrap(&self, stored: &str, new_key: &MasterKey) -> Result<String> {
+        let envelope = Envelope::parse(stored)?;
+        let dek = self.unwrap(&envelope)?;
+        let wrapped = new_key.wrap(&dek)?;
+        Ok(format!("{ENVELOPE_PREFIX}{}:{}:{}", new_key.id, wrapped, envelope.data))
+    }
+
+    fn wrap(&self, dek: &[u8]) -> Result<String> {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let wrapped = self.cipher.encrypt(&nonce, dek).map_err(|_| anyhow!("Failed to wrap data key"))?;
+        Ok(join(&nonce, &wrapped))
+    }
+
+    fn unwrap(&self, envelope: &Envelope) -> Result<Vec<u8>> {
+        if envelope.key_id != self.id {
+            bail!("Token was encrypted with master key {}, current key is {}", envelope.key_id, self.id);
+        }
+        let (nonce, wrapped) = split(envelope.wrapped_key)?;
+        self.cipher
+            .decrypt(Nonce::from_slice(&nonce), wrapped.as_slice())
+            .map_err(|_| anyhow!("Failed to unwrap data key"))
+    }
+}
+
+/// 是否为加密后的值（用于识别需要迁移的旧明文记录）
+pub fn is_encrypted(stored: &str) -> bool {
+    stored.starts_with(ENVELOPE_PREFIX)
+}
+
+struct Envelope<'a> {
+    key_id: &'a str,
+    wrapped_key: &'a str,
+    data: &'a str,
+}
+
+impl<'a> Envelope<'a> {
+    fn parse(stored: &'a str) -> Result<Self> {
+        let rest = stored.strip_prefix(ENVELOPE_PREFIX).ok_or_else(|| anyhow!("Token is not encrypted"))?;
+        let mut parts = rest.splitn(3, ':');
+        match (parts.next(), parts.next(), parts.next()) {
+            (Some(key_id), Some(wrapped_key), Some(data)) => Ok(Self { key_id, wrapped_key, data }),
+            _ => bail!("Malformed encrypted token"),
+        }
+    }
+}
+
+fn join(nonce: &[u8], ciphertext: &[u8]) -> String {
+    let mut buf = Vec::with_capacity(nonce.len() + ciphertext.len());
+    buf.extend_from_slice(nonce);
+    buf.extend_from_slice(ciphertext);
+    B64.encode(buf)
+}
+
+fn split(encoded: &str) -> Result<(Vec<u8>, Vec<u8>)> {
+    let mut bytes = B64.decode(encoded).context("Malformed encrypted token")?;
+    if bytes.len() <= NONCE_LEN {
+        bail!("Malformed encrypted token");
+    }
+    let rest = bytes.split_off(NONCE_LEN);
+    Ok((bytes, rest))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(byte: u8) -> MasterKey {
+        MasterKey::from_base64(&B64.encode([byte; 32])).unwrap()
+    }
+
+    #[test]
+    fn round_trips() {
+        let key = key(1);
+        let stored = key.encrypt("secret-token").unwrap();
+        assert!(is_encrypted(&stored));
+        assert!(stored.starts_with(&format!("{ENVELOPE_PREFIX}{}:", key.id())));
+        assert!(!stored.contains("secret-token"));
+        assert_eq!(key.decrypt(&stored).unwrap(), "secret-token");
+        // 每次加密使用新的 DEK 与 nonce
+        assert_ne!(key.encrypt("secret-token").unwrap(), stored);
+    }
+
+    #[test]
+    fn rewrap_moves_to_new_key() {
+        let (old, new) = (key(1), key(2));
+        let stored = old.encrypt("secret-token").unwrap();
+        let rewrapped = old.rewrap(&stored, &new).unwrap();
+
+        assert_eq!(new.decrypt(&rewrapped).unwrap(), "secret-token");
+        assert!(old.decrypt(&rewrapped).is_err());
+        // 数据密文不变，只替换了密钥 ID 与包裹后的 DEK
+        assert_eq!(stored.rsplit(':').next(), rewrapped.rsplit(':').next());
+        assert!(rewrapped.starts_with(&format!("{ENVELOPE_PREFIX}{}:", new.id())));
+        // 旧密钥无法再包裹已轮换的记录
+        assert!(old.rewrap(&rewrapped, &new).is_err());
+    }
+
+    #[test]
+    fn rejects_wrong_key_and_tampering() {
+        let key1 = key(1);
+        let stored = key1.encrypt("secret-token").unwrap();
+        assert!(key(2).decrypt(&stored).is_err());
+
+        // 冒用密钥 ID 也无法解开 DEK
+        let forged = stored.replacen(key1.id(), key(2).id(), 1);
+        assert!(key(2).decrypt(&forged).is_err());
+
+        let (head, data) = stored.rsplit_once(':').unwrap();
+        let mut bytes = B64.decode(data).unwrap();
+        *bytes.last_mut().unwrap() ^= 1;
+        assert!(key1.decrypt(&format!("{head}:{}", B64.encode(bytes))).is_err());
+    }
+
+    #[test]
+    fn rejects_malformed_input() {
+        let key = key(1);
+        assert!(!is_encrypted("plain-token"));
+        for stored in ["plain-token", "enc:v1:", "enc:v1:abcd:xyz", "enc:v1:abcd:!!:!!", &format!("enc:v1:{}:AAAA:AAAA", key.id())] {
+            assert!(key.decrypt(stored).is_err(), "{stored:?}");
+        }
+        assert!(MasterKey::from_base64("not base64!").is_err());
+        assert!(MasterKey::from_base64(&B64.encode([0u8; 16])).is_err());
+    }
+
+    #[test]
+    fn reads_keys_from_settings() {
+        let encoded = B64.encode([3u8; 32]);
+        let path = std::env::temp_dir().join(format!("master-key-{}", std::process::id()));
+        std::fs::write(&path, format!("{encoded}\n")).unwrap();
+
+        let auth = AuthSettings {
+            master_key: Some(encoded),
+            new_master_key_file: Some(path.to_string_lossy().into_owned()),
+            ..AuthSettings::default()
+        };
+        let current = MasterKey::from_settings(&auth).unwrap();
+        let new = MasterKey::new_from_settings(&auth).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(current.id(), key(3).id());
+        assert_eq!(new.id(), current.id());
+
+        assert!(MasterKey::from_settings(&AuthSettings::default()).is_err());
+        assert!(MasterKey::new_from_settings(&AuthSettings::default()).is_err());
+    }
+}
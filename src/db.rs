@@ -1,44 +1,143 @@
+use actix_web::web;
 use diesel::sqlite::SqliteConnection;
 
-use diesel::Connection;
 use diesel::connection::SimpleConnection;
-use dotenv::dotenv;
-use std::env;
+use diesel::migration::MigrationSource;
+use diesel::r2d2::{ConnectionManager, CustomizeConnection, Pool};
+use diesel::sqlite::Sqlite;
+use diesel_migrations::{embed_migrations, EmbeddedMigrations, MigrationHarness};
 
-pub fn establish_connection() -> SqliteConnection {
-    dotenv().ok();
+use crate::settings::DatabaseSettings;
+use std::collections::HashSet;
+use std::time::Duration;
 
-    let database_url = env::var("DATABASE_URL")
-        .expect("DATABASE_URL must be set in .env file");
+pub type DbPool = Pool<ConnectionManager<SqliteConnection>>;
 
-    let mut conn = SqliteConnection::establish(&database_url)
-        .unwrap_or_else(|_| panic!("Error connecting to {}", database_url));
+/// SQLite 遇到锁时的等待时间
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+/// 健康检查获取连接的最长等待时间
+const CHECK_TIMEOUT: Duration = Duration::from_secs(2);
 
-    // 在首次连接时执行一次性迁移
-    run_migrations(&mut conn);
+/// 每个新建连接的初始化：WAL 模式、忙等待超时与外键约束
+#[derive(Debug)]
+struct ConnectionOptions {
+    busy_timeout: Duration,
+}
 
-    conn
+impl CustomizeConnection<SqliteConnection, diesel::r2d2::Error> for ConnectionOptions {
+    fn on_acquire(&self, conn: &mut SqliteConnection) -> Result<(), diesel::r2d2::Error> {
+        conn.batch_execute(&format!(
+            "PRAGMA journal_mode = WAL; PRAGMA synchronous = NORMAL; PRAGMA busy_timeout = {}; PRAGMA foreign_keys = ON;",
+            self.busy_timeout.as_millis()
+        ))
+        .map_err(diesel::r2d2::Error::QueryError)
+    }
 }
 
-/// 运行简化版迁移：若表不存在则创建
-pub fn run_migrations(conn: &mut SqliteConnection) {
-    let sql = r#"
-        CREATE TABLE IF NOT EXISTS users (
-            id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
-            username TEXT NOT NULL UNIQUE,
-            password_hash TEXT NOT NULL
-        );
-        CREATE TABLE IF NOT EXISTS credentials (
-            id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
-            email TEXT NOT NULL,
-            token TEXT NOT NULL
-        );
-        CREATE TABLE IF NOT EXISTS api_tokens (
-            id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
-            token TEXT NOT NULL,
-            created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+/// 创建全局连接池，并在启动时执行一次迁移
+pub fn init_pool(settings: &DatabaseSettings) -> DbPool {
+    let pool = build_pool(settings);
+    let mut conn = pool.get().expect("Failed to get a connection for migrations");
+    run_migrations(&mut conn).unwrap_or_else(|e| panic!("{e}"));
+
+    pool
+}
+
+/// 创建连接池但不执行迁移（供 `db migrate`、`db backup` 等命令使用）
+pub fn build_pool(settings: &DatabaseSettings) -> DbPool {
+    let database_url = &settings.url;
+    let manager = ConnectionManager::<SqliteConnection>::new(database_url);
+    Pool::builder()
+        .max_size(settings.pool_size)
+        .connection_customizer(Box::new(ConnectionOptions { busy_timeout: BUSY_TIMEOUT }))
+        .build(manager)
+        .unwrap_or_else(|e| panic!("Error connecting to {}: {}", database_url, e))
+}
+
+/// 测试用的内存数据库：单个连接（多个连接会各自打开独立的内存库），已执行迁移
+#[cfg(test)]
+pub fn test_pool() -> DbPool {
+    init_pool(&DatabaseSettings { url: ":memory:".into(), pool_size: 1 })
+}
+
+/// 在阻塞线程池中执行数据库操作，避免 SQLite I/O 与锁等待占用 actix 工作线程
+pub async fn run<F, T>(pool: &DbPool, f: F) -> anyhow::Result<T>
+where
+    F: FnOnce(&DbPool) -> anyhow::Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    let pool = pool.clone();
+    web::block(move || f(&pool))
+        .await
+        .map_err(|e| anyhow::anyhow!("Blocking database task failed: {e}"))?
+}
+
+/// 编译进二进制的版本化迁移（`migrations/` 目录）
+pub const MIGRATIONS: EmbeddedMigrations = embed_migrations!("migrations");
+
+/// 数据库迁移状态
+pub struct MigrationState {
+    /// 已编译进程序但尚未应用的迁移
+    pub pending: Vec<String>,
+    /// 数据库中已应用、但本程序不认识的迁移（库结构比程序新）
+    pub unknown: Vec<String>,
+}
+
+/// 对比内置迁移与数据库中的迁移记录
+pub fn migration_state(conn: &mut SqliteConnection) -> anyhow::Result<MigrationState> {
+    let known: HashSet<String> = MigrationSource::<Sqlite>::migrations(&MIGRATIONS)
+        .map_err(|e| anyhow::anyhow!("Failed to load embedded migrations: {e}"))?
+        .iter()
+        .map(|m| m.name().version().to_string())
+        .collect();
+    let applied: HashSet<String> = conn
+        .applied_migrations()
+        .map_err(|e| anyhow::anyhow!("Failed to read migration history: {e}"))?
+        .iter()
+        .map(|v| v.to_string())
+        .collect();
+    let mut pending: Vec<String> = known.difference(&applied).cloned().collect();
+    let mut unknown: Vec<String> = applied.difference(&known).cloned().collect();
+    pending.sort();
+    unknown.sort();
+    Ok(MigrationState { pending, unknown })
+}
+
+/// 健康检查：确认数据库可以执行查询，并返回迁移状态
+pub fn check(pool: &DbPool) -> anyhow::Result<MigrationState> {
+    let conn = &mut pool.get_timeout(CHECK_TIMEOUT)?;
+    conn.batch_execute("SELECT 1")?;
+    migration_state(conn)
+}
+
+/// 执行尚未应用的迁移并返回其版本；若数据库中存在本程序不认识的迁移（库结构比程序新）则拒绝启动
+pub fn run_migrations(conn: &mut SqliteConnection) -> anyhow::Result<Vec<String>> {
+    let state = migration_state(conn)?;
+    if !state.unknown.is_empty() {
+        anyhow::bail!(
+            "Database schema is ahead of this binary (unknown migrations: {}); refusing to start",
+            state.unknown.join(", ")
         );
-    "#;
+    }
+
+    let versions: Vec<String> = conn
+        .run_pending_migrations(MIGRATIONS)
+        .map_err(|e| anyhow::anyhow!("Failed to run migrations: {e}"))?
+        .iter()
+        .map(|v| v.to_string())
+        .collect();
+    for version in &versions {
+        log::info!("Applied migration {}", version);
+    }
+    Ok(versions)
+}
 
-    conn.batch_execute(sql).expect("Failed to run migrations");
+/// 以 `VACUUM INTO` 将数据库一致地复制到 `path`（目标文件不能已存在）
+pub fn backup(pool: &DbPool, path: &str) -> anyhow::Result<()> {
+    use diesel::RunQueryDsl;
+    let conn = &mut pool.get()?;
+    diesel::sql_query("VACUUM INTO ?")
+        .bind::<diesel::sql_types::Text, _>(path)
+        .execute(conn)?;
+    Ok(())
 }
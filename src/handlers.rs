@@ -1,11 +1,13 @@
-use actix_web::{web, HttpRequest, HttpResponse, Error};
-use futures_util::StreamExt;
+use std::time::Instant;
+
+use actix_web::{http::StatusCode, web, HttpResponse, Error};
+use chrono::{DateTime, Duration, Utc};
 use reqwest::Client;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
+use uuid::Uuid;
 
-
-use crate::services;
+use crate::{accounting::{RequestLogger, RequestRecord}, auth::Principal, crypto::MasterKey, db::{self, DbPool}, limiter::RateLimiter, metrics, models::CatalogModel, selector::CredentialPool, services, settings::Settings, sse};
 
 // Structures for OpenAI compatible requests
 #[derive(Serialize, Deserialize, Clone)]
@@ -20,6 +22,7 @@ pub struct ChatCompletionRequest {
     messages: Vec<ChatMessage>,
     stream: Option<bool>,
     temperature: Option<f32>,
+    max_tokens: Option<u32>,
 }
 
 // Structures for the target Atlassian AI API
@@ -28,6 +31,8 @@ struct AtlassianRequestPayload {
     messages: Vec<ChatMessage>,
     temperature: Option<f32>,
     stream: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_tokens: Option<u32>,
 }
 
 #[derive(Serialize)]
@@ -36,91 +41,518 @@ struct AtlassianPlatformAttrs {
 }
 
 #[derive(Serialize)]
-struct AtlassianRequest {
+pub(crate) struct AtlassianRequest {
     #[serde(rename = "requestPayload")]
     request_payload: AtlassianRequestPayload,
     #[serde(rename = "platformAttributes")]
     platform_attributes: AtlassianPlatformAttrs,
 }
 
-// Health check handler
-pub async fn health() -> HttpResponse {
+impl AtlassianRequest {
+    /// Smallest useful request, used by the credential health prober.
+    pub(crate) fn probe(model: String) -> Self {
+        AtlassianRequest {
+            request_payload: AtlassianRequestPayload {
+                messages: vec![ChatMessage { role: "user".into(), content: "ping".into() }],
+                temperature: None,
+                stream: false,
+                max_tokens: Some(1),
+            },
+            platform_attributes: AtlassianPlatformAttrs { model },
+        }
+    }
+}
+
+// Structures for Atlassian responses. Streaming events and the non-streaming
+// body share the same shape; the payload may be wrapped in `response_payload`
+// or sit at the top level, and message content may be a string or a list of parts.
+#[derive(Deserialize)]
+pub(crate) struct AtlassianResponse {
+    #[serde(alias = "responsePayload")]
+    response_payload: Option<AtlassianResponsePayload>,
+    #[serde(flatten)]
+    inline_payload: AtlassianResponsePayload,
+}
+
+impl AtlassianResponse {
+    pub(crate) fn into_payload(self) -> AtlassianResponsePayload {
+        self.response_payload.unwrap_or(self.inline_payload)
+    }
+}
+
+impl AtlassianResponsePayload {
+    /// Token usage reported by upstream, if any.
+    pub(crate) fn usage(&self) -> Option<Usage> {
+        self.usage.map(Usage::from)
+    }
+}
+
+#[derive(Deserialize, Default)]
+pub(crate) struct AtlassianResponsePayload {
+    id: Option<String>,
+    created: Option<i64>,
+    #[serde(default)]
+    pub(crate) choices: Vec<AtlassianChoice>,
+    usage: Option<AtlassianUsage>,
+}
+
+#[derive(Deserialize, Clone, Copy)]
+struct AtlassianUsage {
+    #[serde(alias = "input_tokens", alias = "promptTokens", alias = "inputTokens")]
+    prompt_tokens: Option<u32>,
+    #[serde(alias = "output_tokens", alias = "completionTokens", alias = "outputTokens")]
+    completion_tokens: Option<u32>,
+    #[serde(alias = "totalTokens")]
+    total_tokens: Option<u32>,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct AtlassianChoice {
+    #[serde(default)]
+    pub(crate) index: u32,
+    #[serde(alias = "delta")]
+    message: Option<AtlassianMessage>,
+    #[serde(alias = "finishReason")]
+    finish_reason: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct AtlassianMessage {
+    role: Option<String>,
+    content: Option<AtlassianContent>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum AtlassianContent {
+    Text(String),
+    Parts(Vec<AtlassianContentPart>),
+}
+
+#[derive(Deserialize)]
+struct AtlassianContentPart {
+    #[serde(default)]
+    text: String,
+}
+
+impl AtlassianChoice {
+    pub(crate) fn role(&self) -> Option<&str> {
+        self.message.as_ref().and_then(|m| m.role.as_deref())
+    }
+
+    /// Concatenated text of the message (or delta), if any.
+    pub(crate) fn text(&self) -> Option<String> {
+        match self.message.as_ref()?.content.as_ref()? {
+            AtlassianContent::Text(text) => Some(text.clone()),
+            AtlassianContent::Parts(parts) => Some(parts.iter().map(|p| p.text.as_str()).collect()),
+        }
+    }
+
+    /// Finish reason mapped onto the OpenAI vocabulary.
+    pub(crate) fn finish_reason(&self) -> Option<String> {
+        let reason = self.finish_reason.as_deref()?.to_ascii_lowercase();
+        let mapped = match reason.as_str() {
+            "" => return None,
+            "end_turn" | "stop_sequence" => "stop",
+            "max_tokens" => "length",
+            other => other,
+        };
+        Some(mapped.to_string())
+    }
+}
+
+// Structures for OpenAI compatible responses
+#[derive(Serialize)]
+struct ChatCompletionResponse {
+    id: String,
+    object: &'static str,
+    created: i64,
+    model: String,
+    choices: Vec<ChatCompletionChoice>,
+    usage: Usage,
+}
+
+#[derive(Serialize)]
+struct ChatCompletionChoice {
+    index: u32,
+    message: ChatMessage,
+    finish_reason: String,
+}
+
+#[derive(Serialize, Default, Clone, Copy)]
+pub(crate) struct Usage {
+    pub(crate) prompt_tokens: u32,
+    pub(crate) completion_tokens: u32,
+    pub(crate) total_tokens: u32,
+}
+
+impl Usage {
+    /// Usage to charge against rate limits: the upstream figures, or a rough
+    /// estimate when upstream reported nothing.
+    pub(crate) fn or_estimate(self, prompt_tokens: u32, completion_chars: usize) -> Self {
+        if self.total_tokens > 0 {
+            return self;
+        }
+        let completion_tokens = estimate_tokens(completion_chars);
+        Usage { prompt_tokens, completion_tokens, total_tokens: prompt_tokens + completion_tokens }
+    }
+}
+
+// Rough token count (about four characters per token)
+pub(crate) fn estimate_tokens(chars: usize) -> u32 {
+    chars.div_ceil(4).min(u32::MAX as usize) as u32
+}
+
+impl From<AtlassianUsage> for Usage {
+    fn from(usage: AtlassianUsage) -> Self {
+        let prompt_tokens = usage.prompt_tokens.unwrap_or(0);
+        let completion_tokens = usage.completion_tokens.unwrap_or(0);
+        Usage {
+            prompt_tokens,
+            completion_tokens,
+            total_tokens: usage.total_tokens.unwrap_or(prompt_tokens + completion_tokens),
+        }
+    }
+}
+
+impl ChatCompletionRequest {
+    fn prompt_tokens(&self) -> u32 {
+        estimate_tokens(self.messages.iter().map(|m| m.content.chars().count()).sum())
+    }
+}
+
+impl ChatCompletionResponse {
+    /// Map an upstream Atlassian payload onto the OpenAI `chat.completion` schema.
+    /// `model` is the model name the client asked for.
+    fn from_atlassian(payload: AtlassianResponsePayload, model: String) -> Self {
+        let choices = payload
+            .choices
+            .into_iter()
+            .map(|choice| ChatCompletionChoice {
+                index: choice.index,
+                message: ChatMessage {
+                    role: choice.role().unwrap_or("assistant").to_string(),
+                    content: choice.text().unwrap_or_default(),
+                },
+                finish_reason: choice.finish_reason().unwrap_or_else(|| "stop".into()),
+            })
+            .collect();
+
+        ChatCompletionResponse {
+            id: payload.id.unwrap_or_else(|| format!("chatcmpl-{}", Uuid::new_v4().simple())),
+            object: "chat.completion",
+            created: payload.created.unwrap_or_else(|| Utc::now().timestamp()),
+            model,
+            choices,
+            usage: payload.usage.map(Usage::from).unwrap_or_default(),
+        }
+    }
+
+    fn completion_chars(&self) -> usize {
+        self.choices.iter().map(|c| c.message.content.chars().count()).sum()
+    }
+}
+
+// Cooldown applied after a 429 without a usable Retry-After header
+const DEFAULT_COOLDOWN_SECS: i64 = 60;
+// Upper bound so a bogus Retry-After cannot park a credential for days
+const MAX_COOLDOWN_SECS: i64 = 3600;
+
+// Parse `Retry-After` (delay-seconds or HTTP-date) into a cooldown duration
+fn retry_after(headers: &reqwest::header::HeaderMap) -> Duration {
+    let value = headers.get(reqwest::header::RETRY_AFTER).and_then(|v| v.to_str().ok()).map(str::trim);
+    let secs = match value {
+        Some(v) => v.parse::<i64>().ok().or_else(|| {
+            DateTime::parse_from_rfc2822(v).ok().map(|at| (at.with_timezone(&Utc) - Utc::now()).num_seconds())
+        }),
+        None => None,
+    };
+    Duration::seconds(secs.unwrap_or(DEFAULT_COOLDOWN_SECS).clamp(1, MAX_COOLDOWN_SECS))
+}
+
+// Update credential health after a non-success upstream response:
+// 429 starts a cooldown, 401/403 disables the credential, anything else is only recorded
+pub(crate) async fn record_upstream_failure(pool: &DbPool, credential_id: i32, response: &reqwest::Response) {
+    let status = response.status().as_u16();
+    let error = format!("Upstream returned {}", response.status());
+    let cooldown = retry_after(response.headers());
+    let recorded = db::run(pool, move |pool| match status {
+        429 => services::cool_down_credential(pool, credential_id, cooldown, &error),
+        401 | 403 => services::disable_credential(pool, credential_id, &error),
+        _ => services::record_credential_failure(pool, credential_id, &error),
+    })
+    .await;
+    if let Err(e) = recorded {
+        log::error!("Failed to record credential health: {}", e);
+    }
+}
+
+// Liveness: the process is up and answering HTTP; no dependencies are checked.
+// Also served at `/health` for existing probes.
+pub async fn health_live() -> HttpResponse {
     HttpResponse::Ok().json(json!({ "status": "ok" }))
 }
 
-// Mock models handler (as before)
-pub async fn list_models() -> HttpResponse {
-    let response = json!({ "object": "list", "data": [ { "id": "gpt-3.5-turbo", "object": "model", "owned_by": "system" } ] });
-    HttpResponse::Ok().json(response)
+// Readiness: the database answers, its schema matches this binary and at least
+// one credential can currently serve traffic. Responds 503 with the breakdown otherwise.
+pub async fn health_ready(pool: web::Data<DbPool>) -> HttpResponse {
+    let schema = db::run(&pool, db::check).await;
+    let (database, migrations) = match schema {
+        Ok(state) => {
+            let up_to_date = state.pending.is_empty() && state.unknown.is_empty();
+            (
+                json!({ "status": "ok" }),
+                json!({ "status": if up_to_date { "ok" } else { "error" }, "pending": state.pending, "unknown": state.unknown }),
+            )
+        }
+        Err(e) => (json!({ "status": "error", "error": e.to_string() }), json!({ "status": "unknown" })),
+    };
+
+    let counts = db::run(&pool, |pool| {
+        Ok((services::list_available_credentials(pool)?.len(), services::list_credentials(pool)?.len()))
+    })
+    .await;
+    let credentials = match counts {
+        Ok((healthy, total)) => json!({ "status": if healthy > 0 { "ok" } else { "error" }, "healthy": healthy, "total": total }),
+        Err(e) => json!({ "status": "error", "error": e.to_string() }),
+    };
+
+    let ready = [&database, &migrations, &credentials].iter().all(|check| check["status"] == "ok");
+    let body = json!({
+        "status": if ready { "ready" } else { "not_ready" },
+        "checks": { "database": database, "migrations": migrations, "credentials": credentials },
+    });
+    if ready {
+        HttpResponse::Ok().json(body)
+    } else {
+        HttpResponse::ServiceUnavailable().json(body)
+    }
+}
+
+// OpenAI-style error body: {"error": {"message", "type", "param", "code"}}
+fn openai_error(status: StatusCode, message: String, error_type: &str, param: Option<&str>, code: &str) -> HttpResponse {
+    HttpResponse::build(status).json(json!({
+        "error": { "message": message, "type": error_type, "param": param, "code": code }
+    }))
+}
+
+fn model_not_found(model: &str) -> HttpResponse {
+    openai_error(
+        StatusCode::NOT_FOUND,
+        format!("The model `{model}` does not exist or you do not have access to it."),
+        "invalid_request_error",
+        Some("model"),
+        "model_not_found",
+    )
+}
+
+fn model_object(model: &CatalogModel) -> serde_json::Value {
+    json!({
+        "id": model.alias,
+        "object": "model",
+        "created": model.created,
+        "owned_by": model.owned_by,
+        "context_window": model.context_window,
+    })
+}
+
+// Models handlers, generated from the model catalog
+pub async fn list_models(pool: web::Data<DbPool>) -> HttpResponse {
+    match db::run(&pool, services::list_models).await {
+        Ok(models) => {
+            let data: Vec<_> = models.iter().map(model_object).collect();
+            HttpResponse::Ok().json(json!({ "object": "list", "data": data }))
+        }
+        Err(e) => {
+            log::error!("Failed to load model catalog: {}", e);
+            HttpResponse::InternalServerError().json(json!({ "error": "Failed to load models" }))
+        }
+    }
+}
+
+pub async fn get_model(path: web::Path<String>, pool: web::Data<DbPool>) -> HttpResponse {
+    let alias = path.into_inner();
+    let lookup = alias.clone();
+    match db::run(&pool, move |pool| services::find_model(pool, &lookup)).await {
+        Ok(Some(model)) => HttpResponse::Ok().json(model_object(&model)),
+        Ok(None) => model_not_found(&alias),
+        Err(e) => {
+            log::error!("Failed to load model catalog: {}", e);
+            HttpResponse::InternalServerError().json(json!({ "error": "Failed to load models" }))
+        }
+    }
+}
+
+// A failed attempt only counts as a failover when another credential is left to try
+fn count_failover(credential_id: i32, has_next: bool) {
+    if has_next {
+        metrics::FAILOVERS.with_label_values(&[credential_id.to_string().as_str()]).inc();
+    }
 }
 
 // The core chat completions proxy handler
+#[allow(clippy::too_many_arguments)] // actix extractors
 pub async fn chat_completions(
-    req: HttpRequest,
+    principal: web::ReqData<Principal>,
     body: web::Json<ChatCompletionRequest>,
     client: web::Data<Client>,
+    pool: web::Data<DbPool>,
+    credential_pool: web::Data<CredentialPool>,
+    master_key: web::Data<MasterKey>,
+    limiter: web::Data<RateLimiter>,
+    logger: web::Data<RequestLogger>,
+    settings: web::Data<Settings>,
 ) -> Result<HttpResponse, Error> {
-    // 1. Validate API Token from Authorization header
-    let auth_header = req.headers().get("Authorization").and_then(|h| h.to_str().ok());
-    if auth_header.is_none() || !auth_header.unwrap().starts_with("Bearer ") {
-        return Ok(HttpResponse::Unauthorized().json(json!({ "error": "API key is required" })));
-    }
-    let token = auth_header.unwrap().strip_prefix("Bearer ").unwrap();
-    if services::validate_api_token(token).is_err() {
-        return Ok(HttpResponse::Unauthorized().json(json!({ "error": "Invalid API key" })));
-    }
+    // 1. The caller was authenticated by the `api_auth` middleware
+    let started = Instant::now();
+    let principal = principal.into_inner();
+    log::debug!("Chat completion for {} with model {}", principal, body.model);
+    // Token usage of API key callers counts towards their tokens-per-minute limit
+    let key_id = match principal {
+        Principal::ApiKey { id, .. } => Some(id),
+        Principal::User { .. } => None,
+    };
+    let prompt_tokens = body.prompt_tokens();
+    let stream = body.stream.unwrap_or(false);
+
+    // 2. Resolve the public model alias to an Atlassian model id
+    let alias = body.model.clone();
+    let upstream_model = match db::run(&pool, move |pool| services::find_model(pool, &alias)).await {
+        Ok(Some(model)) => model.upstream_model,
+        Ok(None) => return Ok(model_not_found(&body.model)),
+        Err(e) => {
+            log::error!("Failed to load model catalog: {}", e);
+            return Ok(HttpResponse::InternalServerError().json(json!({ "error": "Failed to load models" })));
+        }
+    };
 
-    // 2. Get all available credentials from the database
-    let credentials = match services::list_credentials() {
-        Ok(creds) if !creds.is_empty() => creds,
-        _ => return Ok(HttpResponse::InternalServerError().json(json!({ "error": "No credentials configured" }))),
+    // Every request that gets this far is written to the request log
+    let mut record = RequestRecord::new(&principal, &body.model, stream);
+
+    // 3. Get all available credentials from the database, ordered by the selection strategy
+    let credentials = match db::run(&pool, services::list_available_credentials).await {
+        Ok(creds) if !creds.is_empty() => credential_pool.order(creds),
+        Ok(_) => {
+            record.fail(&logger, StatusCode::SERVICE_UNAVAILABLE, "No healthy credentials available");
+            return Ok(HttpResponse::ServiceUnavailable().json(json!({ "error": "No healthy credentials available" })));
+        }
+        Err(e) => {
+            log::error!("Failed to load credentials: {}", e);
+            record.fail(&logger, StatusCode::INTERNAL_SERVER_ERROR, "Failed to load credentials");
+            return Ok(HttpResponse::InternalServerError().json(json!({ "error": "Failed to load credentials" })));
+        }
     };
 
-    // 3. Prepare the request for the target service
+    // 4. Prepare the request for the target service
     let atlassian_req = AtlassianRequest {
         request_payload: AtlassianRequestPayload {
             messages: body.messages.clone(),
             temperature: body.temperature,
-            stream: body.stream.unwrap_or(false),
+            stream,
+            max_tokens: body.max_tokens,
         },
         platform_attributes: AtlassianPlatformAttrs {
-            model: body.model.clone(), // We can add model mapping logic here later
+            model: upstream_model,
         },
     };
 
-    // 4. Loop through credentials and attempt to make a request
-    for credential in credentials {
+    // 5. Loop through credentials and attempt to make a request
+    let last = credentials.len() - 1;
+    for (index, credential) in credentials.into_iter().enumerate() {
+        // Credential tokens are stored encrypted; decrypt only for the upstream call
+        let token = match master_key.decrypt(&credential.token) {
+            Ok(token) => token,
+            Err(e) => {
+                log::error!("Cannot decrypt token for {}: {}", credential.email, e);
+                count_failover(credential.id, index < last);
+                continue;
+            }
+        };
+        let lease = credential_pool.acquire(credential.id);
+        record.attempt(credential.id);
         let request_builder = client
-            .post("https://api.atlassian.com/ai/chat/completions") // Target URL
-            .bearer_auth(&credential.token)
+            .post(settings.upstream.chat_url())
+            .bearer_auth(&token)
             .json(&atlassian_req);
 
-        match request_builder.send().await {
+        let attempt_started = Instant::now();
+        let outcome = request_builder.send().await;
+        let upstream_status = match &outcome {
+            Ok(response) => response.status().as_u16().to_string(),
+            Err(_) => "error".to_string(),
+        };
+        metrics::UPSTREAM_LATENCY
+            .with_label_values(&[body.model.as_str(), upstream_status.as_str()])
+            .observe(attempt_started.elapsed().as_secs_f64());
+
+        match outcome {
             Ok(response) if response.status().is_success() => {
-                // 5. Handle successful response (streaming or non-streaming)
-                if body.stream.unwrap_or(false) {
-                    let stream = response.bytes_stream().map(|item| item.map_err(actix_web::error::ErrorInternalServerError));
+                record.upstream_status(response.status().as_u16());
+                let credential_id = credential.id;
+                if let Err(e) = db::run(&pool, move |pool| services::record_credential_success(pool, credential_id)).await {
+                    log::error!("Failed to record credential health: {}", e);
+                }
+                // 6. Handle successful response (streaming or non-streaming)
+                if stream {
+                    // The lease moves into the stream so the credential counts as in flight until it ends
+                    let (limiter, logger) = (limiter.clone(), logger.clone());
+                    metrics::ACTIVE_STREAMS.inc();
+                    let events = sse::translate(response.bytes_stream(), body.model.clone(), started, prompt_tokens, move |usage, error| {
+                        metrics::ACTIVE_STREAMS.dec();
+                        drop(lease);
+                        if let Some(key_id) = key_id {
+                            limiter.record_tokens(key_id, usage.total_tokens);
+                        }
+                        match error {
+                            None => record.finish(&logger, usage),
+                            Some(error) => record.interrupted(&logger, usage, &error),
+                        }
+                    });
                     return Ok(HttpResponse::Ok()
                         .content_type("text/event-stream")
-                        .streaming(stream));
+                        .insert_header(("Cache-Control", "no-cache"))
+                        .streaming(events));
                 } else {
-                    let response_body = response.json::<serde_json::Value>().await.unwrap();
-                    return Ok(HttpResponse::Ok().json(response_body));
+                    return match response.json::<AtlassianResponse>().await {
+                        Ok(upstream) => {
+                            let completion = ChatCompletionResponse::from_atlassian(upstream.into_payload(), body.model.clone());
+                            let usage = completion.usage.or_estimate(prompt_tokens, completion.completion_chars());
+                            if let Some(key_id) = key_id {
+                                limiter.record_tokens(key_id, usage.total_tokens);
+                            }
+                            record.finish(&logger, usage);
+                            Ok(HttpResponse::Ok().json(completion))
+                        }
+                        Err(e) => {
+                            log::error!("Unreadable upstream response for {}: {}", credential.email, e);
+                            record.fail(&logger, StatusCode::BAD_GATEWAY, &format!("Invalid upstream response: {e}"));
+                            Ok(HttpResponse::BadGateway().json(json!({ "error": "Invalid upstream response" })))
+                        }
+                    };
                 }
             }
             Ok(failed_response) => {
-                // Log error and try next credential
+                // Log error, update credential health and try next credential
                 log::warn!("Credential for {} failed with status: {}", credential.email, failed_response.status());
+                record.upstream_status(failed_response.status().as_u16());
+                record_upstream_failure(&pool, credential.id, &failed_response).await;
+                count_failover(credential.id, index < last);
                 continue;
             }
             Err(e) => {
                 // Log error and try next credential
                 log::error!("Request with credential for {} failed: {}", credential.email, e);
+                let (credential_id, error) = (credential.id, e.to_string());
+                if let Err(e) = db::run(&pool, move |pool| services::record_credential_failure(pool, credential_id, &error)).await {
+                    log::error!("Failed to record credential health: {}", e);
+                }
+                count_failover(credential.id, index < last);
                 continue;
             }
         }
     }
 
     // If all credentials failed
+    record.fail(&logger, StatusCode::BAD_GATEWAY, "All credentials exhausted");
     Ok(HttpResponse::BadGateway().json(json!({ "error": "All credentials exhausted" })))
 }
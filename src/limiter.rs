@@ -0,0 +1,268 @@
+//! API Key 级别的限流：每分钟请求数（RPM）、每分钟 Token 数（TPM）与并发请求上限
+//!
+//! 计数保存在进程内的一分钟滑动窗口中，不落库；多实例部署时各实例独立计数。
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::models::KeyLimits;
+
+/// 滑动窗口长度
+const WINDOW: Duration = Duration::from_secs(60);
+/// 并发超限时建议的重试间隔
+const CONCURRENCY_RETRY: Duration = Duration::from_secs(1);
+
+/// 单个 Key 最近一分钟的用量
+#[derive(Default)]
+struct KeyWindow {
+    requests: VecDeque<Instant>,
+    tokens: VecDeque<(Instant, u32)>,
+    in_flight: u32,
+}
+
+impl KeyWindow {
+    /// 丢弃窗口之外的记录
+    fn prune(&mut self, now: Instant) {
+        while self.requests.front().is_some_and(|at| now.duration_since(*at) >= WINDOW) {
+            self.requests.pop_front();
+        }
+        while self.tokens.front().is_some_and(|(at, _)| now.duration_since(*at) >= WINDOW) {
+            self.tokens.pop_front();
+        }
+    }
+
+    /// 窗口内没有记录且没有未结束的请求，可以整条删除
+    fn is_idle(&self) -> bool {
+        self.in_flight == 0 && self.requests.is_empty() && self.tokens.is_empty()
+    }
+
+    fn used_tokens(&self) -> u32 {
+        self.tokens.iter().fold(0u32, |sum, (_, n)| sum.saturating_add(*n))
+    }
+
+    fn status(&self, limits: &KeyLimits, now: Instant) -> RateStatus {
+        // 窗口内最早一条记录过期的时刻即为额度开始恢复的时刻
+        let reset = |oldest: Option<&Instant>| oldest.map(|at| WINDOW.saturating_sub(now.duration_since(*at))).unwrap_or_default();
+        RateStatus {
+            limit_requests: limits.rpm,
+            remaining_requests: limits.rpm.unwrap_or(0).saturating_sub(self.requests.len() as u32),
+            reset_requests: reset(self.requests.front()),
+            limit_tokens: limits.tpm,
+            remaining_tokens: limits.tpm.unwrap_or(0).saturating_sub(self.used_tokens()),
+            reset_tokens: reset(self.tokens.front().map(|(at, _)| at)),
+        }
+    }
+}
+
+/// 某个 Key 当前的限流状态，用于生成 `x-ratelimit-*` 响应头
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RateStatus {
+    pub limit_requests: Option<u32>,
+    pub remaining_requests: u32,
+    pub reset_requests: Duration,
+    pub limit_tokens: Option<u32>,
+    pub remaining_tokens: u32,
+    pub reset_tokens: Duration,
+}
+
+/// 超出的限额类型
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitKind {
+    Requests,
+    Tokens,
+    Concurrency,
+}
+
+impl LimitKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            LimitKind::Requests => "requests",
+            LimitKind::Tokens => "tokens",
+            LimitKind::Concurrency => "concurrency",
+        }
+    }
+}
+
+/// 请求被拒绝
+#[derive(Debug)]
+pub struct Rejection {
+    pub kind: LimitKind,
+    pub limit: u32,
+    pub retry_after: Duration,
+    pub status: RateStatus,
+}
+
+/// 请求被放行：附带放行后的状态与并发许可
+pub struct Admission {
+    pub status: RateStatus,
+    pub permit: ConcurrencyPermit,
+}
+
+/// 所有 Key 的限流器，通过 `web::Data` 共享
+#[derive(Default)]
+pub struct RateLimiter {
+    windows: Arc<Mutex<HashMap<i32, KeyWindow>>>,
+}
+
+impl RateLimiter {
+    /// 检查并占用一次请求额度；超限时返回拒绝原因，不计入用量
+    pub fn admit(&self, key_id: i32, limits: &KeyLimits) -> Result<Admission, Rejection> {
+        let now = Instant::now();
+        let mut windows = self.windows.lock().unwrap();
+        // 顺带清理已吊销或长期空闲的 Key，避免记录只增不减
+        windows.retain(|_, window| {
+            window.prune(now);
+            !window.is_idle()
+        });
+        let window = windows.entry(key_id).or_default();
+
+        let status = window.status(limits, now);
+        let reject = |kind, limit, retry_after| Err(Rejection { kind, limit, retry_after, status });
+        if let Some(limit) = limits.max_concurrent.filter(|limit| window.in_flight >= *limit) {
+            return reject(LimitKind::Concurrency, limit, CONCURRENCY_RETRY);
+        }
+        if let Some(limit) = limits.rpm.filter(|limit| window.requests.len() as u32 >= *limit) {
+            return reject(LimitKind::Requests, limit, status.reset_requests);
+        }
+        if let Some(limit) = limits.tpm.filter(|limit| window.used_tokens() >= *limit) {
+            return reject(LimitKind::Tokens, limit, status.reset_tokens);
+        }
+
+        window.requests.push_back(now);
+        window.in_flight += 1;
+        Ok(Admission {
+            status: window.status(limits, now),
+            permit: ConcurrencyPermit { windows: self.windows.clone(), key_id },
+        })
+    }
+
+    /// 请求完成后记入实际消耗的 Token 数
+    pub fn record_tokens(&self, key_id: i32, tokens: u32) {
+        if tokens == 0 {
+            return;
+        }
+        let now = Instant::now();
+        let mut windows = self.windows.lock().unwrap();
+        let window = windows.entry(key_id).or_default();
+        window.prune(now);
+        window.tokens.push_back((now, tokens));
+    }
+}
+
+/// 并发许可，drop 时减少该 Key 的并发计数
+pub struct ConcurrencyPermit {
+    windows: Arc<Mutex<HashMap<i32, KeyWindow>>>,
+    key_id: i32,
+}
+
+impl Drop for ConcurrencyPermit {
+    fn drop(&mut self) {
+        if let Some(window) = self.windows.lock().unwrap().get_mut(&self.key_id) {
+            window.in_flight = window.in_flight.saturating_sub(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits(rpm: Option<u32>, tpm: Option<u32>, max_concurrent: Option<u32>) -> KeyLimits {
+        KeyLimits { rpm, tpm, max_concurrent }
+    }
+
+    /// 把某个 Key 的全部记录提前 `age`，模拟时间流逝
+    fn age(limiter: &RateLimiter, key_id: i32, age: Duration) {
+        let mut windows = limiter.windows.lock().unwrap();
+        let window = windows.get_mut(&key_id).unwrap();
+        for at in window.requests.iter_mut().chain(window.tokens.iter_mut().map(|(at, _)| at)) {
+            *at = at.checked_sub(age).unwrap();
+        }
+    }
+
+    #[test]
+    fn unlimited_keys_are_always_admitted() {
+        let limiter = RateLimiter::default();
+        let admissions: Vec<_> = (0..100).map(|_| limiter.admit(1, &KeyLimits::default()).unwrap()).collect();
+        assert_eq!(admissions[99].status.limit_requests, None);
+    }
+
+    #[test]
+    fn rejects_requests_over_rpm_until_window_passes() {
+        let limiter = RateLimiter::default();
+        let limits = limits(Some(3), None, None);
+        let remaining: Vec<u32> = (0..3).map(|_| limiter.admit(1, &limits).unwrap().status.remaining_requests).collect();
+        assert_eq!(remaining, [2, 1, 0]);
+
+        let rejection = limiter.admit(1, &limits).err().unwrap();
+        assert_eq!((rejection.kind, rejection.limit), (LimitKind::Requests, 3));
+        assert!(rejection.retry_after > Duration::from_secs(59) && rejection.retry_after <= WINDOW);
+        // 其他 Key 独立计数
+        assert!(limiter.admit(2, &limits).is_ok());
+
+        age(&limiter, 1, Duration::from_secs(30));
+        let rejection = limiter.admit(1, &limits).err().unwrap();
+        assert!(rejection.retry_after <= Duration::from_secs(30));
+        age(&limiter, 1, Duration::from_secs(30));
+        assert_eq!(limiter.admit(1, &limits).unwrap().status.remaining_requests, 2);
+    }
+
+    #[test]
+    fn rejects_requests_over_tpm() {
+        let limiter = RateLimiter::default();
+        let limits = limits(None, Some(100), None);
+        assert_eq!(limiter.admit(1, &limits).unwrap().status.remaining_tokens, 100);
+        limiter.record_tokens(1, 60);
+        assert_eq!(limiter.admit(1, &limits).unwrap().status.remaining_tokens, 40);
+        limiter.record_tokens(1, 60);
+
+        let rejection = limiter.admit(1, &limits).err().unwrap();
+        assert_eq!((rejection.kind, rejection.limit), (LimitKind::Tokens, 100));
+        assert_eq!(rejection.status.remaining_tokens, 0);
+
+        age(&limiter, 1, WINDOW);
+        assert_eq!(limiter.admit(1, &limits).unwrap().status.remaining_tokens, 100);
+    }
+
+    #[test]
+    fn releases_concurrency_on_drop() {
+        let limiter = RateLimiter::default();
+        let limits = limits(None, None, Some(2));
+        let first = limiter.admit(1, &limits).unwrap();
+        let _second = limiter.admit(1, &limits).unwrap();
+
+        let rejection = limiter.admit(1, &limits).err().unwrap();
+        assert_eq!((rejection.kind, rejection.retry_after), (LimitKind::Concurrency, CONCURRENCY_RETRY));
+        drop(first);
+        assert!(limiter.admit(1, &limits).is_ok());
+    }
+
+    #[test]
+    fn drops_idle_windows() {
+        let limiter = RateLimiter::default();
+        let limits = limits(Some(10), None, None);
+        drop(limiter.admit(1, &limits).unwrap());
+        let _permit = limiter.admit(2, &limits).unwrap();
+        age(&limiter, 1, WINDOW);
+        age(&limiter, 2, WINDOW);
+
+        drop(limiter.admit(3, &limits).unwrap());
+        let windows = limiter.windows.lock().unwrap();
+        // 仍持有并发许可的 Key 保留
+        assert!(!windows.contains_key(&1) && windows.contains_key(&2) && windows.contains_key(&3));
+    }
+
+    #[test]
+    fn rejected_requests_are_not_counted() {
+        let limiter = RateLimiter::default();
+        let limits = limits(Some(1), None, Some(1));
+        let admission = limiter.admit(1, &limits).unwrap();
+        for _ in 0..5 {
+            assert_eq!(limiter.admit(1, &limits).err().unwrap().kind, LimitKind::Concurrency);
+        }
+        drop(admission);
+        assert_eq!(limiter.windows.lock().unwrap()[&1].requests.len(), 1);
+        assert_eq!(limiter.admit(1, &limits).err().unwrap().kind, LimitKind::Requests);
+    }
+}
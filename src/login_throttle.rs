@@ -0,0 +1,375 @@
+//! 管理员登录的暴力破解防护：按用户名与来源 IP 分别统计连续失败次数，
+//! 超过免费次数后指数退避，达到阈值后临时锁定
+//!
+//! 计数保存在进程内，不落库；重启后清零，多实例部署时各实例独立计数。
+//! 锁定事件另行写入 `login_lockouts` 表，供管理员在用户管理页查看与解除。
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use actix_web::http::header::X_FORWARDED_FOR;
+use actix_web::HttpRequest;
+
+use crate::settings::LoginSettings;
+
+/// 计数的维度
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Scope {
+    User,
+    Ip,
+}
+
+impl Scope {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Scope::User => "user",
+            Scope::Ip => "ip",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "user" => Some(Scope::User),
+            "ip" => Some(Scope::Ip),
+            _ => None,
+        }
+    }
+}
+
+/// 并发尝试过多时建议的重试间隔
+const IN_FLIGHT_RETRY: Duration = Duration::from_secs(1);
+
+/// 单个用户名或 IP 的连续失败记录
+struct Attempts {
+    failures: u32,
+    /// 已放行、尚未得出结果的尝试；与已有失败一并计入阈值，防止并发尝试绕过退避与锁定
+    in_flight: u32,
+    last_failure: Instant,
+    /// 在此之前拒绝新的尝试（退避或锁定）
+    blocked_until: Instant,
+}
+
+impl Attempts {
+    fn new(now: Instant) -> Self {
+        Self { failures: 0, in_flight: 0, last_failure: now, blocked_until: now }
+    }
+
+    /// 尚未到期、结果未定或仍在计数窗口内的记录需要保留
+    fn is_live(&self, now: Instant, window: Duration) -> bool {
+        self.in_flight > 0 || self.blocked_until > now || now.duration_since(self.last_failure) < window
+    }
+}
+
+/// 本次失败触发的锁定
+#[derive(Debug)]
+pub struct Lockout {
+    pub scope: Scope,
+    pub subject: String,
+    pub failures: u32,
+    pub duration: Duration,
+}
+
+/// 所有用户名与 IP 的失败计数，通过 `web::Data` 共享
+pub struct LoginThrottle {
+    settings: LoginSettings,
+    attempts: Mutex<HashMap<(Scope, String), Attempts>>,
+}
+
+impl LoginThrottle {
+    pub fn new(settings: &LoginSettings) -> Self {
+        Self { settings: settings.clone(), attempts: Mutex::default() }
+    }
+
+    /// 校验密码等凭据之前调用：检查退避与锁定，并在同一把锁内登记本次尝试。
+    /// 超过免费次数后同一用户名或 IP 同时只放行一次尝试，因此并发请求也无法在锁定生效前多猜。
+    /// 被拒绝时返回需等待的时间；放行时返回的 [`LoginAttempt`] 须以 `failed` 或 `succeeded` 结束，
+    /// 直接丢弃（如只通过了第一步验证）则既不计为失败也不清除计数
+    pub fn begin(&self, ip: &str, username: &str) -> Result<LoginAttempt<'_>, Duration> {
+        let now = Instant::now();
+        let window = self.settings.lockout();
+        let mut attempts = self.attempts.lock().unwrap();
+        // 顺带清理早已过期的记录，避免大量随机用户名撑大内存
+        attempts.retain(|_, a| a.is_live(now, window));
+
+        let mut wait = Duration::ZERO;
+        for (scope, subject, max_failures) in self.scopes(ip, username) {
+            let Some(entry) = attempts.get(&(scope, subject.to_string())) else { continue };
+            let failures = if now.duration_since(entry.last_failure) >= window { 0 } else { entry.failures };
+            let pending = failures + entry.in_flight;
+            let blocked = entry.blocked_until.saturating_duration_since(now);
+            let busy = (max_failures > 0 && pending >= max_failures) || (pending >= self.settings.free_attempts && entry.in_flight > 0);
+            wait = wait.max(if busy { blocked.max(IN_FLIGHT_RETRY) } else { blocked });
+        }
+        if !wait.is_zero() {
+            return Err(wait);
+        }
+
+        for (scope, subject, _) in self.scopes(ip, username) {
+            attempts.entry((scope, subject.to_string())).or_insert_with(|| Attempts::new(now)).in_flight += 1;
+        }
+        Ok(LoginAttempt { throttle: self, ip: ip.to_string(), username: username.to_string(), settled: false })
+    }
+
+    fn scopes<'a>(&self, ip: &'a str, username: &'a str) -> [(Scope, &'a str, u32); 2] {
+        [(Scope::Ip, ip, self.settings.ip_max_failures), (Scope::User, username, self.settings.max_failures)]
+    }
+
+    /// 结束一次尝试：释放并发占位，失败时累加计数并设置退避或锁定，返回本次触发的锁定
+    fn settle(&self, ip: &str, username: &str, outcome: Outcome) -> Vec<Lockout> {
+        let now = Instant::now();
+        let window = self.settings.lockout();
+        let mut attempts = self.attempts.lock().unwrap();
+        let mut lockouts = Vec::new();
+        for (scope, subject, max_failures) in self.scopes(ip, username) {
+            let key = (scope, subject.to_string());
+            let entry = attempts.entry(key.clone()).or_insert_with(|| Attempts::new(now));
+            entry.in_flight = entry.in_flight.saturating_sub(1);
+            match outcome {
+                Outcome::Abandoned => {}
+                // IP 计数保留，防止攻击者用自己的账号重置
+                Outcome::Succeeded if scope == Scope::Ip => {}
+                Outcome::Succeeded => {
+                    entry.failures = 0;
+                    entry.blocked_until = now;
+                }
+                Outcome::Failed => {
+                    // 距上次失败已超过锁定时长，重新计数
+                    if now.duration_since(entry.last_failure) >= window {
+                        entry.failures = 0;
+                    }
+                    entry.failures += 1;
+                    entry.last_failure = now;
+                    if max_failures > 0 && entry.failures >= max_failures {
+                        entry.blocked_until = now + window;
+                        if entry.failures == max_failures {
+                            lockouts.push(Lockout { scope, subject: subject.to_string(), failures: entry.failures, duration: window });
+                        }
+                    } else {
+                        entry.blocked_until = now + self.settings.backoff(entry.failures);
+                    }
+                }
+            }
+            if !entry.is_live(now, window) || (entry.failures == 0 && entry.in_flight == 0) {
+                attempts.remove(&key);
+            }
+        }
+        lockouts
+    }
+
+    /// 计数所用的来源 IP：默认取 TCP 对端地址，不信任可被伪造的 `X-Forwarded-For`；
+    /// 对端是 `trusted_proxies` 中的反向代理时，从该头末尾向前跳过可信代理，取第一个其他地址
+    pub fn client_ip(&self, req: &HttpRequest) -> String {
+        let Some(peer) = req.peer_addr().map(|addr| addr.ip()) else {
+            return "unknown".into();
+        };
+        let forwarded: Vec<&str> = req.headers().get_all(X_FORWARDED_FOR).filter_map(|v| v.to_str().ok()).collect();
+        self.forwarded_client(peer, &forwarded.join(",")).to_string()
+    }
+
+    fn forwarded_client(&self, peer: IpAddr, forwarded_for: &str) -> IpAddr {
+        let mut client = peer.to_canonical();
+        for hop in forwarded_for.rsplit(',') {
+            if !self.settings.trusted_proxies.iter().any(|proxy| proxy.contains(client)) {
+                break;
+            }
+            match hop.trim().parse::<IpAddr>() {
+                Ok(ip) => client = ip.to_canonical(),
+                Err(_) => break,
+            }
+        }
+        client
+    }
+
+    /// 管理员手动解除锁定；正在进行的尝试仍按原样结束
+    pub fn unlock(&self, scope: Scope, subject: &str) {
+        if let Some(entry) = self.attempts.lock().unwrap().get_mut(&(scope, subject.to_string())) {
+            entry.failures = 0;
+            entry.blocked_until = Instant::now();
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Outcome {
+    Failed,
+    Succeeded,
+    Abandoned,
+}
+
+/// 已放行的一次登录尝试，见 [`LoginThrottle::begin`]
+pub struct LoginAttempt<'a> {
+    throttle: &'a LoginThrottle,
+    ip: String,
+    username: String,
+    settled: bool,
+}
+
+impl LoginAttempt<'_> {
+    pub fn ip(&self) -> &str {
+        &self.ip
+    }
+
+    /// 验证失败（密码或第二步验证错误、用户不存在都算），返回本次触发的锁定
+    pub fn failed(mut self) -> Vec<Lockout> {
+        self.settled = true;
+        self.throttle.settle(&self.ip, &self.username, Outcome::Failed)
+    }
+
+    /// 登录成功：清除该用户名的失败计数
+    pub fn succeeded(mut self) {
+        self.settled = true;
+        self.throttle.settle(&self.ip, &self.username, Outcome::Succeeded);
+    }
+}
+
+impl Drop for LoginAttempt<'_> {
+    fn drop(&mut self) {
+        if !self.settled {
+            self.throttle.settle(&self.ip, &self.username, Outcome::Abandoned);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Barrier;
+
+    use actix_web::test::TestRequest;
+
+    use super::*;
+    use crate::settings::IpRange;
+
+    fn settings() -> LoginSettings {
+        LoginSettings {
+            free_attempts: 2,
+            backoff_base_secs: 10,
+            max_backoff_secs: 40,
+            max_failures: 4,
+            ip_max_failures: 100,
+            lockout_secs: 900,
+            trusted_proxies: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn backoff_doubles_after_free_attempts() {
+        let s = settings();
+        let secs: Vec<u64> = (0..6).map(|n| s.backoff(n).as_secs()).collect();
+        assert_eq!(secs, [0, 0, 10, 20, 40, 40]);
+    }
+
+    #[test]
+    fn free_attempts_are_not_delayed() {
+        let throttle = LoginThrottle::new(&settings());
+        throttle.begin("1.1.1.1", "alice").unwrap().failed();
+        throttle.begin("1.1.1.1", "alice").unwrap().failed();
+        let wait = throttle.begin("1.1.1.1", "alice").err().unwrap();
+        assert!(wait > Duration::from_secs(9) && wait <= Duration::from_secs(10));
+        // 同一 IP 换用户名同样要等待，其他 IP 的其他用户名不受影响
+        assert!(throttle.begin("1.1.1.1", "bob").is_err());
+        assert!(throttle.begin("2.2.2.2", "bob").is_ok());
+    }
+
+    #[test]
+    fn locks_out_after_max_failures() {
+        let throttle = LoginThrottle::new(&LoginSettings { backoff_base_secs: 0, ..settings() });
+        let lockouts: Vec<Lockout> = (0..4).flat_map(|_| throttle.begin("1.1.1.1", "alice").unwrap().failed()).collect();
+        assert_eq!(lockouts.len(), 1);
+        assert_eq!((lockouts[0].scope, lockouts[0].subject.as_str(), lockouts[0].failures), (Scope::User, "alice", 4));
+
+        let wait = throttle.begin("2.2.2.2", "alice").err().unwrap();
+        assert!(wait > Duration::from_secs(890));
+        throttle.unlock(Scope::User, "alice");
+        assert!(throttle.begin("2.2.2.2", "alice").is_ok());
+    }
+
+    #[test]
+    fn success_resets_user_but_not_ip() {
+        let throttle = LoginThrottle::new(&LoginSettings { backoff_base_secs: 0, ..settings() });
+        for _ in 0..3 {
+            throttle.begin("1.1.1.1", "alice").unwrap().failed();
+        }
+        throttle.begin("1.1.1.1", "alice").unwrap().succeeded();
+        let attempts = throttle.attempts.lock().unwrap();
+        assert!(!attempts.contains_key(&(Scope::User, "alice".into())));
+        assert_eq!(attempts[&(Scope::Ip, "1.1.1.1".into())].failures, 3);
+    }
+
+    #[test]
+    fn abandoned_attempt_is_not_a_failure() {
+        let throttle = LoginThrottle::new(&settings());
+        drop(throttle.begin("1.1.1.1", "alice").unwrap());
+        assert!(throttle.attempts.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn in_flight_attempts_count_towards_limits() {
+        let throttle = LoginThrottle::new(&settings());
+        let admitted: Vec<_> = (0..50).filter_map(|_| throttle.begin("1.1.1.1", "alice").ok()).collect();
+        assert_eq!(admitted.len(), 2);
+        for attempt in admitted {
+            attempt.failed();
+        }
+        assert!(throttle.begin("1.1.1.1", "alice").is_err());
+    }
+
+    #[test]
+    fn concurrent_attempts_cannot_exceed_lockout() {
+        let throttle = LoginThrottle::new(&LoginSettings { free_attempts: 100, ..settings() });
+        let threads = 64;
+        let barrier = Barrier::new(threads);
+        let admitted = std::thread::scope(|scope| {
+            let handles: Vec<_> = (0..threads)
+                .map(|_| {
+                    scope.spawn(|| {
+                        let attempt = throttle.begin("1.1.1.1", "alice").ok();
+                        // 所有请求都完成检查后才得出结果，模拟 Argon2 校验期间的并发
+                        barrier.wait();
+                        attempt.map(LoginAttempt::failed).is_some()
+                    })
+                })
+                .collect();
+            handles.into_iter().map(|h| h.join().unwrap()).filter(|admitted| *admitted).count()
+        });
+        assert_eq!(admitted, 4);
+        assert!(throttle.begin("1.1.1.1", "alice").is_err());
+    }
+
+    #[test]
+    fn parses_ip_ranges() {
+        let range: IpRange = "172.16.0.0/12".parse().unwrap();
+        assert!(range.contains("172.31.255.1".parse().unwrap()));
+        assert!(range.contains("::ffff:172.20.0.1".parse().unwrap()));
+        assert!(!range.contains("172.32.0.1".parse().unwrap()));
+        assert!("10.0.0.1".parse::<IpRange>().unwrap().contains("10.0.0.1".parse().unwrap()));
+        assert!(!"10.0.0.1".parse::<IpRange>().unwrap().contains("10.0.0.2".parse().unwrap()));
+        assert!("fd00::/8".parse::<IpRange>().unwrap().contains("fd12::1".parse().unwrap()));
+        assert!("0.0.0.0/0".parse::<IpRange>().unwrap().contains("8.8.8.8".parse().unwrap()));
+        for invalid in ["", "10.0.0.0/33", "fd00::/129", "10.0.0.0/", "example.com"] {
+            assert!(invalid.parse::<IpRange>().is_err(), "{invalid:?}");
+        }
+    }
+
+    #[test]
+    fn reads_forwarded_for_only_from_trusted_proxies() {
+        let throttle = LoginThrottle::new(&LoginSettings { trusted_proxies: vec!["10.0.0.0/8".parse().unwrap()], ..settings() });
+        let client_ip = |peer: &str, forwarded: Option<&str>| {
+            let mut req = TestRequest::default().peer_addr(peer.parse().unwrap());
+            if let Some(forwarded) = forwarded {
+                req = req.insert_header((X_FORWARDED_FOR, forwarded));
+            }
+            throttle.client_ip(&req.to_http_request())
+        };
+        assert_eq!(client_ip("10.0.0.2:1234", Some("1.2.3.4")), "1.2.3.4");
+        // 客户端自带的 X-Forwarded-For 在最左侧，只采信由可信代理追加的部分
+        assert_eq!(client_ip("10.0.0.2:1234", Some("6.6.6.6, 1.2.3.4, 10.0.0.5")), "1.2.3.4");
+        assert_eq!(client_ip("10.0.0.2:1234", Some("10.0.0.9")), "10.0.0.9");
+        assert_eq!(client_ip("10.0.0.2:1234", Some("garbage")), "10.0.0.2");
+        assert_eq!(client_ip("10.0.0.2:1234", None), "10.0.0.2");
+        // 不可信的对端无法伪造来源
+        assert_eq!(client_ip("5.5.5.5:1234", Some("1.2.3.4")), "5.5.5.5");
+        assert_eq!(client_ip("[::ffff:5.5.5.5]:1234", None), "5.5.5.5");
+    }
+}
@@ -1,4 +1,4 @@
-use actix_web::{web, App, HttpServer, HttpResponse, middleware::Logger};
+use actix_web::{web, App, HttpServer, HttpResponse, middleware::{from_fn, Logger}};
 mod db;
 mod auth;
 mod models;
@@ -9,36 +9,126 @@ mod schema;
 mod handlers;
 mod admin_handlers;
 mod services;
+mod crypto;
+mod selector;
+mod limiter;
+mod accounting;
+mod report;
+mod metrics;
+mod prober;
+mod sse;
+mod settings;
+mod cli;
+mod totp;
+mod login_throttle;
 
 use auth::auth_handler;
 use serde_json::json;
 use repository::ensure_admin_exists;
-use middleware::jwt;
-use handlers::{list_models, chat_completions, health};
+use middleware::{admin_guard, api_auth, jwt, rate_limit};
+use handlers::{list_models, get_model, chat_completions, health_live, health_ready};
 use reqwest::Client;
-use admin_handlers::{show_login, handle_login, show_credentials, add_credential, delete_credential, generate_api_token};
+use admin_handlers::{show_login, handle_login, show_change_password, handle_change_password, show_login_totp, handle_login_totp, show_totp, start_totp_setup, confirm_totp, regenerate_recovery_codes, disable_totp, reset_user_totp, show_credentials, add_credential, delete_credential, enable_credential, test_credential, show_api_keys, generate_api_token, update_api_token, revoke_api_token, add_model, delete_model, show_usage, export_usage, show_users, create_user, update_user_role, disable_user, enable_user, delete_user, unlock_login};
+use selector::CredentialPool;
+use limiter::RateLimiter;
+use login_throttle::LoginThrottle;
+use accounting::RequestLogger;
+use crypto::MasterKey;
 use tera::Tera;
 use actix_files as fs;
+use clap::Parser;
+use settings::{Cli, Settings};
+use cli::Command;
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    std::env::set_var("RUST_LOG", "actix_web=info");
-    env_logger::init();
+    // 默认输出 info 级别，可用 RUST_LOG 覆盖
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
 
-    let _conn = db::establish_connection();
+    // 默认值 < 配置文件 < 环境变量 < 命令行参数
+    let cli = Cli::parse();
+    let settings = Settings::load(&cli).unwrap_or_else(|e| panic!("{e:#}"));
+    log::debug!("Loaded settings: {:?}", settings);
+
+    match cli.command.unwrap_or(Command::Serve) {
+        Command::Serve => serve(settings).await,
+        command => {
+            if let Err(e) = cli::run(command, &settings) {
+                eprintln!("Error: {e:#}");
+                std::process::exit(1);
+            }
+            Ok(())
+        }
+    }
+}
+
+/// 启动 HTTP 服务
+async fn serve(settings: Settings) -> std::io::Result<()> {
+    // 全局数据库连接池（启动时执行迁移）
+    let pool = db::init_pool(&settings.database);
+    // 管理员 JWT 的签名密钥
+    let jwt_keys = auth::JwtKeys::from_settings(&settings.auth).unwrap_or_else(|e| panic!("{e:#}"));
+    log::info!("Signing admin JWTs with key id {}", jwt_keys.active_kid());
+    // 凭据 Token 加密所用的主密钥
+    let master_key = MasterKey::from_settings(&settings.auth).unwrap_or_else(|e| panic!("{e:#}"));
+    // 将旧版明文凭据 Token 加密
+    match services::encrypt_plaintext_credentials(&pool, &master_key) {
+        Ok(0) => {}
+        Ok(n) => log::info!("已加密 {} 个旧版明文凭据", n),
+        Err(e) => panic!("Failed to encrypt legacy credentials: {e}"),
+    }
     // 确保存在 admin 用户
-    if let Ok(Some(initial_pwd)) = ensure_admin_exists() {
+    if let Ok(Some(initial_pwd)) = ensure_admin_exists(&pool) {
         println!("🔐 初始管理员密码: {} (请及时修改)", initial_pwd);
     }
+    // 将旧版明文 API Key 转为哈希存储
+    match services::hash_legacy_api_tokens(&pool, settings.auth.api_key_secret()) {
+        Ok(0) => {}
+        Ok(n) => log::info!("已将 {} 个旧版 API Key 转为哈希存储", n),
+        Err(e) => panic!("Failed to hash legacy API keys: {e}"),
+    }
+    // 模型目录为空时写入默认别名
+    if let Ok(n) = services::seed_default_models(&pool) {
+        if n > 0 {
+            log::info!("已写入 {} 个默认模型别名", n);
+        }
+    }
     
-    let tera = Tera::new("templates/**/*").expect("Error parsing templates");
-    let client = Client::new();
+    let tera = Tera::new(&format!("{}/**/*", settings.server.template_dir)).expect("Error parsing templates");
+    let client = Client::builder()
+        .connect_timeout(settings.upstream.connect_timeout())
+        .read_timeout(settings.upstream.read_timeout())
+        .build()
+        .expect("Failed to build HTTP client");
+    let credential_pool = web::Data::new(CredentialPool::from_settings(&settings.upstream));
+    let rate_limiter = web::Data::new(RateLimiter::default());
+    let login_throttle = web::Data::new(LoginThrottle::new(&settings.login));
+    // 后台定期检查凭据健康状态
+    prober::spawn(client.clone(), pool.clone(), master_key.clone(), settings.clone());
+
+    // 请求记账由后台任务异步写库
+    let request_logger = web::Data::new(RequestLogger::spawn(pool.clone()));
+
+    let pool = web::Data::new(pool);
+    let master_key = web::Data::new(master_key);
+    let jwt_keys = web::Data::new(jwt_keys);
+    let bind = settings.server.bind.clone();
+    let static_dir = settings.server.static_dir.clone();
+    let settings = web::Data::new(settings);
 
     HttpServer::new(move || {
         let tera = tera.clone();
         App::new()
             .app_data(web::Data::new(tera.clone()))
             .app_data(web::Data::new(client.clone()))
+            .app_data(pool.clone())
+            .app_data(credential_pool.clone())
+            .app_data(master_key.clone())
+            .app_data(jwt_keys.clone())
+            .app_data(rate_limiter.clone())
+            .app_data(login_throttle.clone())
+            .app_data(request_logger.clone())
+            .app_data(settings.clone())
             .wrap(Logger::default())
             .app_data(web::JsonConfig::default().error_handler(|err, _| {
                 actix_web::error::InternalError::from_response(
@@ -47,30 +137,74 @@ async fn main() -> std::io::Result<()> {
                 ).into()
             }))
             .route("/", web::get().to(|| async { "Atlassian Rust Docker" }))
-            .service(fs::Files::new("/static", "static").show_files_listing())
+            // Prometheus 指标，配置 auth.metrics_token 后需 Bearer 认证
+            .route("/metrics", web::get().to(metrics::export))
+            .service(fs::Files::new("/static", &static_dir).show_files_listing())
             .service(
                 web::scope("/admin")
+                    // 会话与角色检查，见 `middleware::required_role`
+                    .wrap(from_fn(admin_guard))
                     .route("/login", web::get().to(show_login))
                     .route("/login", web::post().to(handle_login))
+                    .route("/login/totp", web::get().to(show_login_totp))
+                    .route("/login/totp", web::post().to(handle_login_totp))
+                    .route("/totp", web::get().to(show_totp))
+                    .route("/totp/setup", web::post().to(start_totp_setup))
+                    .route("/totp/confirm", web::post().to(confirm_totp))
+                    .route("/totp/recovery_codes", web::post().to(regenerate_recovery_codes))
+                    .route("/totp/disable", web::post().to(disable_totp))
+                    .route("/password", web::get().to(show_change_password))
+                    .route("/password", web::post().to(handle_change_password))
                     .route("/credentials", web::get().to(show_credentials))
                     .route("/credentials", web::post().to(add_credential))
                     .route("/credential/{id}/delete", web::post().to(delete_credential))
-                    .route("/api_token/generate", web::post().to(generate_api_token))
+                    .route("/credential/{id}/enable", web::post().to(enable_credential))
+                    .route("/credential/{id}/test", web::post().to(test_credential))
+                    .route("/api_keys", web::get().to(show_api_keys))
+                    .route("/api_keys", web::post().to(generate_api_token))
+                    .route("/api_key/{id}/update", web::post().to(update_api_token))
+                    .route("/api_key/{id}/revoke", web::post().to(revoke_api_token))
+                    .route("/models", web::post().to(add_model))
+                    .route("/model/{id}/delete", web::post().to(delete_model))
+                    .route("/usage", web::get().to(show_usage))
+                    .route("/usage.csv", web::get().to(export_usage))
+                    .route("/users", web::get().to(show_users))
+                    .route("/users", web::post().to(create_user))
+                    .route("/user/{id}/role", web::post().to(update_user_role))
+                    .route("/user/{id}/disable", web::post().to(disable_user))
+                    .route("/user/{id}/enable", web::post().to(enable_user))
+                    .route("/user/{id}/reset_totp", web::post().to(reset_user_totp))
+                    .route("/user/{id}/delete", web::post().to(delete_user))
+                    .route("/lockout/{id}/unlock", web::post().to(unlock_login))
             )
             .service(
                 web::scope("/api")
                     .route("/auth", web::post().to(auth_handler))
-                    .route("/health", web::get().to(health))
+                    .route("/auth/password", web::post().to(auth::change_password_handler))
+                    .route("/health", web::get().to(health_live))
+                    .route("/health/live", web::get().to(health_live))
+                    .route("/health/ready", web::get().to(health_ready))
+                    .service(
+                        // OpenAI 兼容接口：接受 API Key 或管理员 JWT
+                        web::scope("/v1")
+                            .wrap(api_auth())
+                            .route("/models", web::get().to(list_models))
+                            .route("/models/{id}", web::get().to(get_model))
+                            .service(
+                                // 按 API Key 限流
+                                web::resource("/chat/completions")
+                                    .wrap(from_fn(rate_limit))
+                                    .route(web::post().to(chat_completions))
+                            )
+                    )
                     .service(
-                        web::scope("")
+                        web::resource("/protected")
                             .wrap(jwt())
-                            .route("/v1/models", web::get().to(list_models))
-                            .route("/v1/chat/completions", web::post().to(chat_completions))
-                            .route("/protected", web::get().to(|| async { "Protected route" }))
+                            .route(web::get().to(|| async { "Protected route" }))
                     )
             )
     })
-    .bind("127.0.0.1:8080")?
+    .bind(&bind)?
     .run()
     .await
 }
@@ -0,0 +1,147 @@
+//! Prometheus 指标：各模块直接更新全局指标，`/metrics` 在抓取时补充凭据与连接池的即时状态
+
+use std::sync::LazyLock;
+
+use actix_web::{web, HttpRequest, HttpResponse};
+use chrono::Utc;
+use prometheus::{
+    register_histogram_vec, register_int_counter_vec, register_int_gauge, register_int_gauge_vec, Encoder, HistogramVec,
+    IntCounterVec, IntGauge, IntGaugeVec, TextEncoder,
+};
+use sha2::{Digest, Sha256};
+
+use crate::db::{self, DbPool};
+use crate::services;
+use crate::settings::Settings;
+
+/// 上游调用耗时与首 Token 时间的分桶（秒）
+const LATENCY_BUCKETS: &[f64] = &[0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 20.0, 40.0, 60.0, 120.0];
+
+/// 代理请求数，`status` 为返回给客户端的 HTTP 状态码
+pub static REQUESTS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!("proxy_requests_total", "Chat completion requests handled by the proxy", &["model", "status"])
+        .unwrap()
+});
+
+/// 单次上游调用耗时（至收到响应头），`status` 为上游状态码，网络错误为 `error`
+pub static UPSTREAM_LATENCY: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        "proxy_upstream_request_duration_seconds",
+        "Latency of upstream Atlassian calls until response headers",
+        &["model", "status"],
+        LATENCY_BUCKETS.to_vec()
+    )
+    .unwrap()
+});
+
+/// 流式请求从收到请求到输出第一个内容片段的时间
+pub static TIME_TO_FIRST_TOKEN: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        "proxy_time_to_first_token_seconds",
+        "Time from request arrival to the first streamed content chunk",
+        &["model"],
+        LATENCY_BUCKETS.to_vec()
+    )
+    .unwrap()
+});
+
+/// 某个凭据失败后转而尝试下一个凭据的次数
+pub static FAILOVERS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!("proxy_credential_failovers_total", "Upstream attempts that failed over to the next credential", &["credential"])
+        .unwrap()
+});
+
+/// 被限流拒绝的请求数
+pub static RATE_LIMITED: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!("proxy_rate_limited_total", "Requests rejected by per-key rate limits", &["limit"]).unwrap()
+});
+
+/// 管理员登录失败次数，`stage` 为 `password` 或 `totp`
+pub static LOGIN_FAILURES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!("admin_login_failures_total", "Failed admin login attempts", &["stage"]).unwrap()
+});
+
+/// 登录锁定次数，`scope` 为 `user` 或 `ip`
+pub static LOGIN_LOCKOUTS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!("admin_login_lockouts_total", "Temporary lockouts after repeated login failures", &["scope"]).unwrap()
+});
+
+/// 正在进行中的流式响应
+pub static ACTIVE_STREAMS: LazyLock<IntGauge> =
+    LazyLock::new(|| register_int_gauge!("proxy_active_streams", "Streaming responses currently in progress").unwrap());
+
+/// 凭据状态，当前状态为 1，其余为 0
+static CREDENTIAL_STATE: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    register_int_gauge_vec!("proxy_credential_state", "Current health state of each credential", &["credential", "state"]).unwrap()
+});
+
+static DB_POOL_CONNECTIONS: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    register_int_gauge_vec!("proxy_db_pool_connections", "Database pool connections by state", &["state"]).unwrap()
+});
+
+static DB_POOL_MAX: LazyLock<IntGauge> =
+    LazyLock::new(|| register_int_gauge!("proxy_db_pool_max_connections", "Configured database pool size").unwrap());
+
+/// `GET /metrics`：设置了 `auth.metrics_token` 时要求 `Authorization: Bearer <token>`
+pub async fn export(req: HttpRequest, pool: web::Data<DbPool>, settings: web::Data<Settings>) -> HttpResponse {
+    if let Some(expected) = &settings.auth.metrics_token {
+        let provided = req
+            .headers()
+            .get("Authorization")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .unwrap_or("");
+        // 比较摘要而非原文，避免按字节提前返回泄露 Token 内容
+        if Sha256::digest(provided.as_bytes()) != Sha256::digest(expected.as_bytes()) {
+            return HttpResponse::Unauthorized()
+                .append_header(("WWW-Authenticate", "Bearer"))
+                .body("Unauthorized");
+        }
+    }
+
+    refresh(&pool).await;
+
+    let mut buffer = Vec::new();
+    let encoder = TextEncoder::new();
+    if let Err(e) = encoder.encode(&prometheus::gather(), &mut buffer) {
+        log::error!("Failed to encode metrics: {}", e);
+        return HttpResponse::InternalServerError().finish();
+    }
+    HttpResponse::Ok().content_type(encoder.format_type()).body(buffer)
+}
+
+/// 抓取前更新凭据状态与连接池使用情况
+async fn refresh(pool: &DbPool) {
+    // 确保尚未更新过的指标也已注册，抓取结果中始终包含完整的指标列表
+    LazyLock::force(&REQUESTS);
+    LazyLock::force(&UPSTREAM_LATENCY);
+    LazyLock::force(&TIME_TO_FIRST_TOKEN);
+    LazyLock::force(&FAILOVERS);
+    LazyLock::force(&RATE_LIMITED);
+    LazyLock::force(&ACTIVE_STREAMS);
+    LazyLock::force(&LOGIN_FAILURES);
+    LazyLock::force(&LOGIN_LOCKOUTS);
+
+    let state = pool.state();
+    DB_POOL_CONNECTIONS.with_label_values(&["idle"]).set(state.idle_connections as i64);
+    DB_POOL_CONNECTIONS
+        .with_label_values(&["in_use"])
+        .set(state.connections.saturating_sub(state.idle_connections) as i64);
+    DB_POOL_MAX.set(pool.max_size() as i64);
+
+    match db::run(pool, services::list_credentials).await {
+        Ok(credentials) => {
+            // 重新生成，已删除的凭据不再出现
+            CREDENTIAL_STATE.reset();
+            let now = Utc::now().naive_utc();
+            for credential in credentials {
+                let current = credential.state(now);
+                let id = credential.id.to_string();
+                for state in ["active", "cooldown", "disabled"] {
+                    CREDENTIAL_STATE.with_label_values(&[id.as_str(), state]).set((state == current) as i64);
+                }
+            }
+        }
+        Err(e) => log::error!("Failed to load credentials for metrics: {}", e),
+    }
+}
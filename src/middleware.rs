@@ -1,23 +1,275 @@
-use crate::auth::validate_token;
-use actix_web::{dev::ServiceRequest, Error};
+use crate::auth::{JwtKeys, Principal};
+use crate::models::Role;
+use crate::db::{self, DbPool};
+use crate::settings::Settings;
+use crate::limiter::{ConcurrencyPermit, LimitKind, RateLimiter, RateStatus, Rejection};
+use crate::{admin_handlers, metrics, repository, services};
+use actix_web::body::{BodySize, BoxBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderMap, HeaderName, HeaderValue, LOCATION, RETRY_AFTER};
+use actix_web::http::{Method, StatusCode};
+use actix_web::middleware::Next;
+use actix_web::web::Bytes;
+use actix_web::{error::InternalError, web, Error, HttpMessage, HttpResponse};
 use actix_web_httpauth::{extractors::bearer::BearerAuth, middleware::HttpAuthentication};
+use serde_json::json;
 use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tera::Tera;
 
 /// Bearer 认证验证器
 pub async fn validator(
     req: ServiceRequest,
     credentials: BearerAuth,
 ) -> Result<ServiceRequest, (Error, ServiceRequest)> {
-    if validate_token(credentials.token()).is_ok() {
+    let Some(jwt_keys) = req.app_data::<web::Data<JwtKeys>>() else {
+        return Err((actix_web::error::ErrorInternalServerError("JWT keys not configured"), req));
+    };
+    // 须先修改密码的 Token 只能用于修改密码
+    if jwt_keys.validate_token(credentials.token()).is_ok_and(|claims| !claims.must_change_password) {
         Ok(req)
     } else {
         Err((actix_web::error::ErrorUnauthorized("Invalid token"), req))
     }
 }
 
+/// OpenAI 兼容接口的认证验证器：接受 API Key 或管理员 JWT，
+/// 并将解析出的 [`Principal`] 写入请求扩展，供 handler 通过 `web::ReqData` 读取。
+/// 管理员 JWT 不受 API Key 限流约束，因此至少需要 operator 角色，只读的 viewer 被拒绝
+pub async fn api_validator(
+    req: ServiceRequest,
+    credentials: BearerAuth,
+) -> Result<ServiceRequest, (Error, ServiceRequest)> {
+    let token = credentials.token().to_string();
+    let (Some(settings), Some(pool), Some(jwt_keys)) = (
+        req.app_data::<web::Data<Settings>>().cloned(),
+        req.app_data::<web::Data<DbPool>>().cloned(),
+        req.app_data::<web::Data<JwtKeys>>().cloned(),
+    ) else {
+        return Err((actix_web::error::ErrorInternalServerError("Application state not configured"), req));
+    };
+
+    let principal = if let Some(claims) = jwt_keys.validate_token(&token).ok().filter(|c| !c.must_change_password) {
+        // 停用用户或修改密码前签发的 Token 随即失效；角色以数据库中的当前角色为准
+        let username = claims.sub.clone();
+        let user = db::run(&pool, move |pool| repository::get_user_by_username(pool, &username))
+            .await
+            .ok()
+            .filter(|user| user.is_active() && user.accepts_token(claims.token_version));
+        if let Some(user) = user.as_ref().filter(|u| u.role() < Role::Operator) {
+            log::warn!("user:{} ({}) denied {} {}", user.username, user.role().as_str(), req.method(), req.path());
+            return Err((insufficient_role(), req));
+        }
+        user.map(|user| Principal::User { username: user.username })
+    } else {
+        let secret = settings.auth.api_key_secret().to_string();
+        db::run(&pool, move |pool| services::validate_api_token(pool, &secret, &token))
+            .await
+            .ok()
+            .map(|api_token| Principal::ApiKey { id: api_token.id, limits: api_token.limits(), name: api_token.name })
+    };
+
+    match principal {
+        Some(principal) => {
+            req.extensions_mut().insert(principal);
+            Ok(req)
+        }
+        None => Err((invalid_api_key(), req)),
+    }
+}
+
+/// 管理后台的会话与角色检查（包裹整个 `/admin` scope）：
+/// 未登录、用户已停用或会话签发后密码已修改时跳转登录页，须修改密码时只允许访问修改密码页，其余按 [`required_role`] 检查角色。
+/// 通过后将以数据库中当前角色为准的 [`Claims`] 写入请求扩展，供 handler 通过 `web::ReqData` 读取
+pub async fn admin_guard(req: ServiceRequest, next: Next<BoxBody>) -> Result<ServiceResponse<BoxBody>, Error> {
+    if req.path() == "/admin/login" || req.path() == "/admin/login/totp" {
+        return next.call(req).await;
+    }
+    let claims = match (req.cookie("admin_jwt"), req.app_data::<web::Data<JwtKeys>>()) {
+        (Some(cookie), Some(jwt_keys)) => jwt_keys.validate_token(cookie.value()).ok(),
+        _ => None,
+    };
+    let (Some(mut claims), Some(pool)) = (claims, req.app_data::<web::Data<DbPool>>().cloned()) else {
+        return Ok(req.into_response(redirect("/admin/login")));
+    };
+    let username = claims.sub.clone();
+    let user = db::run(&pool, move |pool| repository::get_user_by_username(pool, &username))
+        .await
+        .ok()
+        .filter(|user| user.is_active() && user.accepts_token(claims.token_version));
+    let Some(user) = user else {
+        return Ok(req.into_response(redirect("/admin/login")));
+    };
+    claims.role = user.role();
+    claims.must_change_password = user.must_change_password;
+
+    if claims.must_change_password && req.path() != "/admin/password" {
+        return Ok(req.into_response(redirect("/admin/password")));
+    }
+    if claims.role < required_role(req.method(), req.path()) {
+        log::warn!("user:{} ({}) denied {} {}", claims.sub, claims.role.as_str(), req.method(), req.path());
+        let res = match req.app_data::<web::Data<Tera>>() {
+            Some(tmpl) => admin_handlers::render_error(tmpl, "权限不足"),
+            None => HttpResponse::Forbidden().finish(),
+        };
+        let (http_req, _) = req.into_parts();
+        let mut res = ServiceResponse::new(http_req, res);
+        *res.response_mut().status_mut() = StatusCode::FORBIDDEN;
+        return Ok(res);
+    }
+
+    req.extensions_mut().insert(claims);
+    next.call(req).await
+}
+
+/// `/admin` 下各路由所需的最低角色：用户管理与解除登录锁定需要 owner，其余写操作需要 operator，
+/// 只读页面、修改自己的密码与两步验证设置任何角色均可
+fn required_role(method: &Method, path: &str) -> Role {
+    if path == "/admin/password" || path.starts_with("/admin/totp") {
+        Role::Viewer
+    } else if path.starts_with("/admin/user") || path.starts_with("/admin/lockout") {
+        Role::Owner
+    } else if method == Method::GET {
+        Role::Viewer
+    } else {
+        Role::Operator
+    }
+}
+
+fn redirect(location: &str) -> HttpResponse {
+    HttpResponse::Found().append_header((LOCATION, location)).finish()
+}
+
+fn invalid_api_key() -> Error {
+    let body = json!({
+        "error": {
+            "message": "Invalid API key or token",
+            "type": "invalid_request_error",
+            "param": null,
+            "code": "invalid_api_key",
+        }
+    });
+    InternalError::from_response("Invalid API key", HttpResponse::Unauthorized().json(body)).into()
+}
+
+fn insufficient_role() -> Error {
+    let body = json!({
+        "error": {
+            "message": "This account's role does not allow API access; an operator or owner account is required",
+            "type": "invalid_request_error",
+            "param": null,
+            "code": "insufficient_permissions",
+        }
+    });
+    InternalError::from_response("Insufficient role", HttpResponse::Forbidden().json(body)).into()
+}
+
+/// 按 API Key 执行 RPM / TPM / 并发限制（需位于 [`api_auth`] 之内）；管理员 JWT（operator 及以上）不受限
+pub async fn rate_limit(req: ServiceRequest, next: Next<BoxBody>) -> Result<ServiceResponse<BoxBody>, Error> {
+    let principal = req.extensions().get::<Principal>().cloned();
+    let limiter = req.app_data::<web::Data<RateLimiter>>().cloned();
+    let (Some(Principal::ApiKey { id, limits, .. }), Some(limiter)) = (principal, limiter) else {
+        return next.call(req).await;
+    };
+
+    match limiter.admit(id, &limits) {
+        Ok(admission) => {
+            let mut res = next.call(req).await?;
+            insert_rate_headers(res.headers_mut(), &admission.status);
+            // 许可随响应体一起释放，流式响应在流结束前一直占用并发额度
+            let permit = admission.permit;
+            Ok(res.map_body(|_, body| PermitBody { body, _permit: permit }.boxed()))
+        }
+        Err(rejection) => {
+            log::warn!("Rate limit ({:?}) reached for api_key:{}", rejection.kind, id);
+            metrics::RATE_LIMITED.with_label_values(&[rejection.kind.as_str()]).inc();
+            Ok(req.into_response(rate_limited(&rejection)))
+        }
+    }
+}
+
+/// OpenAI 风格的 429 响应
+fn rate_limited(rejection: &Rejection) -> HttpResponse {
+    let wait = format_duration(rejection.retry_after);
+    let (message, error_type) = match rejection.kind {
+        LimitKind::Requests => (format!("Rate limit reached for requests per minute (RPM): Limit {}. Please try again in {wait}.", rejection.limit), "requests"),
+        LimitKind::Tokens => (format!("Rate limit reached for tokens per minute (TPM): Limit {}. Please try again in {wait}.", rejection.limit), "tokens"),
+        LimitKind::Concurrency => (format!("Too many concurrent requests: Limit {}. Please try again in {wait}.", rejection.limit), "requests"),
+    };
+    let mut res = HttpResponse::TooManyRequests().json(json!({
+        "error": {
+            "message": message,
+            "type": error_type,
+            "param": null,
+            "code": "rate_limit_exceeded",
+        }
+    }));
+    let headers = res.headers_mut();
+    insert_rate_headers(headers, &rejection.status);
+    let secs = rejection.retry_after.as_secs_f64().ceil().max(1.0) as u64;
+    headers.insert(RETRY_AFTER, HeaderValue::from(secs));
+    res
+}
+
+/// 写入 `x-ratelimit-*` 响应头；未设置的限额不输出
+fn insert_rate_headers(headers: &mut HeaderMap, status: &RateStatus) {
+    let mut insert = |name: &'static str, value: String| {
+        if let Ok(value) = HeaderValue::from_str(&value) {
+            headers.insert(HeaderName::from_static(name), value);
+        }
+    };
+    if let Some(limit) = status.limit_requests {
+        insert("x-ratelimit-limit-requests", limit.to_string());
+        insert("x-ratelimit-remaining-requests", status.remaining_requests.to_string());
+        insert("x-ratelimit-reset-requests", format_duration(status.reset_requests));
+    }
+    if let Some(limit) = status.limit_tokens {
+        insert("x-ratelimit-limit-tokens", limit.to_string());
+        insert("x-ratelimit-remaining-tokens", status.remaining_tokens.to_string());
+        insert("x-ratelimit-reset-tokens", format_duration(status.reset_tokens));
+    }
+}
+
+/// 与 OpenAI 相同的时长写法，如 `250ms`、`12s`、`1m0s`
+fn format_duration(duration: Duration) -> String {
+    if duration < Duration::from_secs(1) {
+        return format!("{}ms", duration.as_millis());
+    }
+    let secs = duration.as_secs_f64().ceil() as u64;
+    if secs < 60 {
+        format!("{secs}s")
+    } else {
+        format!("{}m{}s", secs / 60, secs % 60)
+    }
+}
+
+/// 持有并发许可的响应体，响应体 drop（发送完毕或客户端断开）时释放许可
+struct PermitBody {
+    body: BoxBody,
+    _permit: ConcurrencyPermit,
+}
+
+impl MessageBody for PermitBody {
+    type Error = Box<dyn std::error::Error>;
+
+    fn size(&self) -> BodySize {
+        self.body.size()
+    }
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Bytes, Self::Error>>> {
+        Pin::new(&mut self.body).poll_next(cx)
+    }
+}
+
 // Helper to create middleware instance
 type ValidatorFuture = std::pin::Pin<Box<dyn Future<Output = Result<ServiceRequest, (Error, ServiceRequest)>> + 'static>>;
 
 pub fn jwt() -> HttpAuthentication<BearerAuth, fn(ServiceRequest, BearerAuth) -> ValidatorFuture> {
     HttpAuthentication::bearer(|req, creds| Box::pin(validator(req, creds)))
 }
+
+pub fn api_auth() -> HttpAuthentication<BearerAuth, fn(ServiceRequest, BearerAuth) -> ValidatorFuture> {
+    HttpAuthentication::bearer(|req, creds| Box::pin(api_validator(req, creds)))
+}
@@ -1,13 +1,80 @@
+use chrono::NaiveDateTime;
 use diesel::prelude::*;
-use serde::Serialize;
-use crate::schema::{users, credentials, api_tokens};
+use serde::{Deserialize, Serialize};
+use crate::schema::{users, user_recovery_codes, credentials, api_tokens, catalog_models, request_log, login_lockouts};
 
 #[derive(Queryable, Identifiable, Serialize)]
 #[diesel(table_name = users)]
 pub struct User {
     pub id: i32,
     pub username: String,
+    #[serde(skip_serializing)]
     pub password_hash: String,
+    /// 登录后必须先修改密码
+    pub must_change_password: bool,
+    /// 见 [`Role`]
+    pub role: String,
+    pub disabled_at: Option<NaiveDateTime>,
+    /// 以主密钥信封加密的 TOTP 密钥；已生成但 `totp_enabled_at` 为空表示尚未完成绑定
+    #[serde(skip_serializing)]
+    pub totp_secret: Option<String>,
+    pub totp_enabled_at: Option<NaiveDateTime>,
+    /// 最近一次通过验证的 TOTP 时间步
+    #[serde(skip_serializing)]
+    pub totp_last_step: Option<i64>,
+    /// 修改或重置密码时递增，旧版本的 Token 随即失效
+    #[serde(skip_serializing)]
+    pub token_version: i32,
+}
+
+impl User {
+    /// 未知的角色按只读处理
+    pub fn role(&self) -> Role {
+        Role::parse(&self.role).unwrap_or_default()
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.disabled_at.is_none()
+    }
+
+    /// Token 签发后密码未被修改或重置过
+    pub fn accepts_token(&self, token_version: i32) -> bool {
+        self.token_version == token_version
+    }
+
+    /// 登录时需要第二步验证
+    pub fn totp_enabled(&self) -> bool {
+        self.totp_enabled_at.is_some() && self.totp_secret.is_some()
+    }
+}
+
+/// 管理后台角色，按权限从低到高排列
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// 只读：查看凭据、API Key 与用量
+    #[default]
+    Viewer,
+    /// 管理凭据、模型与 API Key
+    Operator,
+    /// 全部权限，包括用户管理
+    Owner,
+}
+
+impl Role {
+    pub const ALL: [Role; 3] = [Role::Viewer, Role::Operator, Role::Owner];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Role::Viewer => "viewer",
+            Role::Operator => "operator",
+            Role::Owner => "owner",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|r| r.as_str() == value)
+    }
 }
 
 #[derive(Insertable)]
@@ -15,14 +82,99 @@ pub struct User {
 pub struct NewUser<'a> {
     pub username: &'a str,
     pub password_hash: &'a str,
+    pub must_change_password: bool,
+    pub role: &'a str,
+}
+
+/// 两步验证恢复码，只保存 Argon2 哈希
+#[derive(Queryable, Identifiable)]
+#[diesel(table_name = user_recovery_codes)]
+pub struct RecoveryCode {
+    pub id: i32,
+    pub user_id: i32,
+    pub code_hash: String,
+    pub used_at: Option<NaiveDateTime>,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = user_recovery_codes)]
+pub struct NewRecoveryCode {
+    pub user_id: i32,
+    pub code_hash: String,
 }
 
+/// 登录失败过多触发的临时锁定记录
 #[derive(Queryable, Identifiable, Serialize)]
+#[diesel(table_name = login_lockouts)]
+pub struct LoginLockout {
+    pub id: i32,
+    pub created_at: NaiveDateTime,
+    /// `user` 或 `ip`
+    pub scope: String,
+    pub subject: String,
+    pub ip: String,
+    pub failures: i32,
+    pub locked_until: NaiveDateTime,
+    pub cleared_at: Option<NaiveDateTime>,
+}
+
+impl LoginLockout {
+    /// 锁定仍在生效（未到期且未被手动解除）
+    pub fn is_active(&self, now: NaiveDateTime) -> bool {
+        self.cleared_at.is_none() && self.locked_until > now
+    }
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = login_lockouts)]
+pub struct NewLoginLockout<'a> {
+    pub created_at: NaiveDateTime,
+    pub scope: &'a str,
+    pub subject: &'a str,
+    pub ip: &'a str,
+    pub failures: i32,
+    pub locked_until: NaiveDateTime,
+}
+
+#[derive(Queryable, Identifiable, Serialize, Clone)]
 #[diesel(table_name = credentials)]
 pub struct Credential {
     pub id: i32,
     pub email: String,
+    /// 以主密钥信封加密后的 Token，不参与序列化
+    #[serde(skip_serializing)]
     pub token: String,
+    pub weight: i32,
+    pub status: String,
+    pub cooldown_until: Option<NaiveDateTime>,
+    pub failure_count: i32,
+    pub last_error: Option<String>,
+    pub last_checked_at: Option<NaiveDateTime>,
+    pub last_check_latency_ms: Option<i32>,
+    pub last_check_result: Option<String>,
+}
+
+/// 凭据状态：可用
+pub const CREDENTIAL_ACTIVE: &str = "active";
+/// 凭据状态：上游返回 401/403 后被禁用，需要人工重新启用
+pub const CREDENTIAL_DISABLED: &str = "disabled";
+
+impl Credential {
+    /// 当前时刻是否处于冷却期
+    pub fn is_cooling_down(&self, now: NaiveDateTime) -> bool {
+        self.cooldown_until.is_some_and(|until| until > now)
+    }
+
+    /// 当前健康状态：`active`、`cooldown` 或 `disabled`
+    pub fn state(&self, now: NaiveDateTime) -> &'static str {
+        if self.status == CREDENTIAL_DISABLED {
+            "disabled"
+        } else if self.is_cooling_down(now) {
+            "cooldown"
+        } else {
+            "active"
+        }
+    }
 }
 
 #[derive(Insertable)]
@@ -30,17 +182,161 @@ pub struct Credential {
 pub struct NewCredential<'a> {
     pub email: &'a str,
     pub token: &'a str,
+    pub weight: i32,
 }
 
 #[derive(Queryable, Identifiable, Serialize)]
 #[diesel(table_name = api_tokens)]
 pub struct ApiToken {
     pub id: i32,
-    pub token: String,
+    /// 引入哈希存储前的明文 Key，启动时迁移后清空
+    #[serde(skip_serializing)]
+    pub legacy_token: Option<String>,
+    /// 可见前缀，用于展示与查找
+    pub token_prefix: String,
+    /// 带密钥的 HMAC-SHA256（十六进制）
+    #[serde(skip_serializing)]
+    pub token_hash: String,
+    pub created_at: Option<NaiveDateTime>,
+    pub name: String,
+    pub owner: String,
+    pub last_used_at: Option<NaiveDateTime>,
+    pub expires_at: Option<NaiveDateTime>,
+    pub revoked_at: Option<NaiveDateTime>,
+    /// 每分钟请求数上限
+    pub rpm_limit: Option<i32>,
+    /// 每分钟 Token 数上限
+    pub tpm_limit: Option<i32>,
+    /// 同时进行中的请求（含流式响应）上限
+    pub max_concurrent: Option<i32>,
+}
+
+impl ApiToken {
+    /// 未吊销且未过期
+    pub fn is_usable(&self, now: NaiveDateTime) -> bool {
+        self.revoked_at.is_none() && self.expires_at.is_none_or(|at| at > now)
+    }
+
+    /// 当前状态：`active`、`expired` 或 `revoked`
+    pub fn state(&self, now: NaiveDateTime) -> &'static str {
+        if self.revoked_at.is_some() {
+            "revoked"
+        } else if !self.is_usable(now) {
+            "expired"
+        } else {
+            "active"
+        }
+    }
+
+    pub fn limits(&self) -> KeyLimits {
+        KeyLimits {
+            rpm: positive(self.rpm_limit),
+            tpm: positive(self.tpm_limit),
+            max_concurrent: positive(self.max_concurrent),
+        }
+    }
+}
+
+/// API Key 的限流配置，`None` 表示不限制
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct KeyLimits {
+    pub rpm: Option<u32>,
+    pub tpm: Option<u32>,
+    pub max_concurrent: Option<u32>,
+}
+
+impl KeyLimits {
+    /// 写回数据库时的列值
+    pub fn columns(&self) -> (Option<i32>, Option<i32>, Option<i32>) {
+        let column = |v: Option<u32>| v.map(|v| v.min(i32::MAX as u32) as i32);
+        (column(self.rpm), column(self.tpm), column(self.max_concurrent))
+    }
+}
+
+fn positive(value: Option<i32>) -> Option<u32> {
+    value.filter(|v| *v > 0).map(|v| v as u32)
 }
 
 #[derive(Insertable)]
 #[diesel(table_name = api_tokens)]
-pub struct NewApiToken {
-    pub token: String,
+pub struct NewApiToken<'a> {
+    pub token_prefix: &'a str,
+    pub token_hash: String,
+    pub name: &'a str,
+    pub owner: &'a str,
+    pub expires_at: Option<NaiveDateTime>,
+    pub rpm_limit: Option<i32>,
+    pub tpm_limit: Option<i32>,
+    pub max_concurrent: Option<i32>,
+}
+
+#[derive(Queryable, Identifiable, Serialize)]
+#[diesel(table_name = catalog_models)]
+pub struct CatalogModel {
+    pub id: i32,
+    pub alias: String,
+    pub upstream_model: String,
+    pub owned_by: String,
+    pub context_window: Option<i32>,
+    pub created: i64,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = catalog_models)]
+pub struct NewCatalogModel<'a> {
+    pub alias: &'a str,
+    pub upstream_model: &'a str,
+    pub owned_by: &'a str,
+    pub context_window: Option<i32>,
+    pub created: i64,
+}
+
+/// 一次代理请求的记账记录
+#[derive(Insertable, Debug)]
+#[diesel(table_name = request_log)]
+pub struct NewRequestLog {
+    /// 请求到达时间
+    pub created_at: NaiveDateTime,
+    /// 管理员 JWT 调用时为空
+    pub api_token_id: Option<i32>,
+    pub principal: String,
+    /// 最后一次尝试（成功时即为实际使用）的凭据
+    pub credential_id: Option<i32>,
+    pub model: String,
+    pub prompt_tokens: i32,
+    pub completion_tokens: i32,
+    /// 从收到请求到响应（流式为整个流）结束的耗时
+    pub latency_ms: i32,
+    /// 最后一次上游响应的状态码，网络错误或未调用上游时为空
+    pub upstream_status: Option<i32>,
+    pub stream: bool,
+    /// 尝试过的凭据数，大于 1 表示发生了故障转移
+    pub attempts: i32,
+    pub error: Option<String>,
+}
+
+/// `request_log` 按天、API Key、模型与凭据聚合后的一行
+#[derive(QueryableByName, Debug)]
+pub struct UsageRow {
+    /// `YYYY-MM-DD`（UTC）
+    #[diesel(sql_type = diesel::sql_types::Text)]
+    pub day: String,
+    #[diesel(sql_type = diesel::sql_types::Nullable<diesel::sql_types::Integer>)]
+    pub api_token_id: Option<i32>,
+    #[diesel(sql_type = diesel::sql_types::Text)]
+    pub model: String,
+    #[diesel(sql_type = diesel::sql_types::Nullable<diesel::sql_types::Integer>)]
+    pub credential_id: Option<i32>,
+    #[diesel(sql_type = diesel::sql_types::BigInt)]
+    pub requests: i64,
+    #[diesel(sql_type = diesel::sql_types::BigInt)]
+    pub prompt_tokens: i64,
+    #[diesel(sql_type = diesel::sql_types::BigInt)]
+    pub completion_tokens: i64,
+    /// 以错误结束的请求数
+    #[diesel(sql_type = diesel::sql_types::BigInt)]
+    pub failed: i64,
+    /// 耗时之和，用于计算平均耗时
+    #[diesel(sql_type = diesel::sql_types::BigInt)]
+    pub latency_ms: i64,
 }
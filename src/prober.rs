@@ -0,0 +1,98 @@
+//! 后台凭据健康检查：定期用每个凭据发送一次低成本请求，并据此更新凭据状态
+
+use std::time::{Duration, Instant};
+
+use reqwest::Client;
+
+use crate::crypto::MasterKey;
+use crate::db::{self, DbPool};
+use crate::handlers::{self, AtlassianRequest};
+use crate::models::Credential;
+use crate::services;
+use crate::settings::Settings;
+
+/// 启动后台检查任务，间隔由 `probe.interval_secs` 配置，0 表示关闭
+pub fn spawn(client: Client, pool: DbPool, master_key: MasterKey, settings: Settings) {
+    let secs = settings.probe.interval_secs;
+    if secs == 0 {
+        log::info!("Credential health prober disabled");
+        return;
+    }
+
+    actix_web::rt::spawn(async move {
+        let mut ticker = tokio::time::interval(Duration::from_secs(secs));
+        loop {
+            ticker.tick().await;
+            probe_all(&client, &pool, &master_key, &settings).await;
+        }
+    });
+}
+
+/// 检查所有凭据；冷却中的凭据跳过，以免浪费上游配额
+pub async fn probe_all(client: &Client, pool: &DbPool, master_key: &MasterKey, settings: &Settings) {
+    let credentials = match db::run(pool, services::list_credentials).await {
+        Ok(creds) => creds,
+        Err(e) => {
+            log::error!("Prober failed to load credentials: {}", e);
+            return;
+        }
+    };
+    let now = chrono::Utc::now().naive_utc();
+    for credential in credentials.iter().filter(|c| !c.is_cooling_down(now)) {
+        if let Err(e) = probe(client, pool, master_key, settings, credential).await {
+            log::error!("Probe for {} failed: {}", credential.email, e);
+        }
+    }
+}
+
+/// 检查单个凭据，记录耗时与结果，并自动切换其状态
+pub async fn probe(
+    client: &Client,
+    pool: &DbPool,
+    master_key: &MasterKey,
+    settings: &Settings,
+    credential: &Credential,
+) -> anyhow::Result<()> {
+    let model = probe_model(pool, settings.probe.model.clone()).await?;
+    let token = master_key.decrypt(&credential.token)?;
+    let started = Instant::now();
+    let outcome = client
+        .post(settings.upstream.chat_url())
+        .bearer_auth(&token)
+        .timeout(Duration::from_secs(settings.probe.timeout_secs))
+        .json(&AtlassianRequest::probe(model))
+        .send()
+        .await;
+    let latency_ms = started.elapsed().as_millis().min(i32::MAX as u128) as i32;
+
+    let result = match outcome {
+        Ok(response) if response.status().is_success() => {
+            let credential_id = credential.id;
+            db::run(pool, move |pool| services::record_probe_success(pool, credential_id)).await?;
+            "ok".to_string()
+        }
+        Ok(response) => {
+            handlers::record_upstream_failure(pool, credential.id, &response).await;
+            format!("HTTP {}", response.status().as_u16())
+        }
+        Err(e) => {
+            let (credential_id, error) = (credential.id, e.to_string());
+            db::run(pool, move |pool| services::record_credential_failure(pool, credential_id, &error)).await?;
+            "network error".to_string()
+        }
+    };
+    log::info!("Probe for {}: {} ({} ms)", credential.email, result, latency_ms);
+    let credential_id = credential.id;
+    db::run(pool, move |pool| services::record_probe(pool, credential_id, latency_ms, &result)).await
+}
+
+/// 检查所用的 Atlassian 模型：`probe.model` 指定的别名，否则取目录中的第一个模型
+async fn probe_model(pool: &DbPool, alias: Option<String>) -> anyhow::Result<String> {
+    let model = match alias {
+        Some(alias) => db::run(pool, move |pool| services::find_model(pool, &alias)).await?,
+        None => db::run(pool, services::list_models).await?.into_iter().next(),
+    };
+    model
+        .map(|m| m.upstream_model)
+        .ok_or_else(|| anyhow::anyhow!("No model available for probing"))
+}
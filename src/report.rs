@@ -0,0 +1,226 @@
+//! 用量报表：把 `request_log` 的聚合结果整理为管理页面所需的表格、SVG 柱状图与 CSV
+
+use std::collections::HashMap;
+
+use chrono::NaiveDate;
+use serde::Serialize;
+
+use crate::models::{ApiToken, Credential, UsageRow};
+
+/// 柱状图画布尺寸（SVG 用户单位）
+const CHART_WIDTH: f64 = 720.0;
+const CHART_HEIGHT: f64 = 160.0;
+
+/// 将 API Key 与凭据的 ID 解析为可读名称
+pub struct Labels {
+    keys: HashMap<i32, String>,
+    credentials: HashMap<i32, String>,
+}
+
+impl Labels {
+    pub fn new(keys: &[ApiToken], credentials: &[Credential]) -> Self {
+        Self {
+            keys: keys.iter().map(|k| (k.id, format!("{} ({}…)", k.name, k.token_prefix))).collect(),
+            credentials: credentials.iter().map(|c| (c.id, c.email.clone())).collect(),
+        }
+    }
+
+    fn key(&self, id: Option<i32>) -> String {
+        match id {
+            None => "管理员 JWT".to_string(),
+            Some(id) => self.keys.get(&id).cloned().unwrap_or_else(|| format!("#{id}")),
+        }
+    }
+
+    fn credential(&self, id: Option<i32>) -> String {
+        match id {
+            None => "-".to_string(),
+            Some(id) => self.credentials.get(&id).cloned().unwrap_or_else(|| format!("#{id} (已删除)")),
+        }
+    }
+}
+
+/// 一组请求的合计
+#[derive(Serialize, Default, Clone, Copy)]
+pub struct Totals {
+    pub requests: i64,
+    pub prompt_tokens: i64,
+    pub completion_tokens: i64,
+    pub total_tokens: i64,
+    pub failed: i64,
+    pub avg_latency_ms: i64,
+    #[serde(skip)]
+    latency_ms: i64,
+}
+
+impl Totals {
+    fn add(&mut self, row: &UsageRow) {
+        self.requests += row.requests;
+        self.prompt_tokens += row.prompt_tokens;
+        self.completion_tokens += row.completion_tokens;
+        self.total_tokens += row.prompt_tokens + row.completion_tokens;
+        self.failed += row.failed;
+        self.latency_ms += row.latency_ms;
+        self.avg_latency_ms = if self.requests > 0 { self.latency_ms / self.requests } else { 0 };
+    }
+}
+
+/// 分组表格中的一行
+#[derive(Serialize)]
+pub struct GroupLine {
+    pub label: String,
+    #[serde(flatten)]
+    pub totals: Totals,
+    /// Token 数占该维度最大值的百分比，用于行内条形图
+    pub share: f64,
+}
+
+/// 服务端渲染的柱状图
+#[derive(Serialize)]
+pub struct Chart {
+    pub width: f64,
+    pub height: f64,
+    pub max: i64,
+    pub bars: Vec<Bar>,
+}
+
+#[derive(Serialize)]
+pub struct Bar {
+    pub label: String,
+    pub value: i64,
+    pub x: f64,
+    pub y: f64,
+    pub width: f64,
+    pub height: f64,
+}
+
+impl Chart {
+    fn new(points: Vec<(String, i64)>) -> Self {
+        let max = points.iter().map(|(_, v)| *v).max().unwrap_or(0);
+        let slot = CHART_WIDTH / points.len().max(1) as f64;
+        let bars = points
+            .into_iter()
+            .enumerate()
+            .map(|(i, (label, value))| {
+                let height = if max > 0 { value as f64 / max as f64 * CHART_HEIGHT } else { 0.0 };
+                Bar {
+                    label,
+                    value,
+                    x: round(i as f64 * slot + slot * 0.1),
+                    y: round(CHART_HEIGHT - height),
+                    width: round(slot * 0.8),
+                    height: round(height),
+                }
+            })
+            .collect();
+        Self { width: CHART_WIDTH, height: CHART_HEIGHT, max, bars }
+    }
+}
+
+/// 保留两位小数，缩短生成的 SVG
+fn round(v: f64) -> f64 {
+    (v * 100.0).round() / 100.0
+}
+
+/// 用量页面的全部数据
+#[derive(Serialize)]
+pub struct UsageReport {
+    pub totals: Totals,
+    pub request_chart: Chart,
+    pub token_chart: Chart,
+    pub by_key: Vec<GroupLine>,
+    pub by_model: Vec<GroupLine>,
+    pub by_credential: Vec<GroupLine>,
+}
+
+impl UsageReport {
+    /// `rows` 为 `[from, to]` 闭区间内的聚合行；没有请求的日期以 0 补齐
+    pub fn build(rows: &[UsageRow], labels: &Labels, from: NaiveDate, to: NaiveDate) -> Self {
+        let mut totals = Totals::default();
+        let mut days: HashMap<&str, Totals> = HashMap::new();
+        let mut by_key: HashMap<String, Totals> = HashMap::new();
+        let mut by_model: HashMap<String, Totals> = HashMap::new();
+        let mut by_credential: HashMap<String, Totals> = HashMap::new();
+        for row in rows {
+            totals.add(row);
+            days.entry(&row.day).or_default().add(row);
+            by_key.entry(labels.key(row.api_token_id)).or_default().add(row);
+            by_model.entry(row.model.clone()).or_default().add(row);
+            by_credential.entry(labels.credential(row.credential_id)).or_default().add(row);
+        }
+
+        let daily: Vec<(String, Totals)> = from
+            .iter_days()
+            .take_while(|day| *day <= to)
+            .map(|day| {
+                let key = day.format("%Y-%m-%d").to_string();
+                let day_totals = days.get(key.as_str()).copied().unwrap_or_default();
+                (key, day_totals)
+            })
+            .collect();
+
+        Self {
+            totals,
+            request_chart: Chart::new(daily.iter().map(|(day, t)| (day.clone(), t.requests)).collect()),
+            token_chart: Chart::new(daily.iter().map(|(day, t)| (day.clone(), t.total_tokens)).collect()),
+            by_key: group_lines(by_key),
+            by_model: group_lines(by_model),
+            by_credential: group_lines(by_credential),
+        }
+    }
+}
+
+/// 按 Token 数、请求数降序排列
+fn group_lines(groups: HashMap<String, Totals>) -> Vec<GroupLine> {
+    let max = groups.values().map(|t| t.total_tokens).max().unwrap_or(0);
+    let mut lines: Vec<GroupLine> = groups
+        .into_iter()
+        .map(|(label, totals)| {
+            let share = if max > 0 { totals.total_tokens as f64 / max as f64 * 100.0 } else { 0.0 };
+            GroupLine { label, totals, share }
+        })
+        .collect();
+    lines.sort_by(|a, b| {
+        (b.totals.total_tokens, b.totals.requests, &a.label).cmp(&(a.totals.total_tokens, a.totals.requests, &b.label))
+    });
+    lines
+}
+
+/// 导出为 CSV：每天 × API Key × 模型 × 凭据一行，供内部计费使用
+pub fn to_csv(rows: &[UsageRow], labels: &Labels) -> String {
+    let mut out = String::from(
+        "date,api_key_id,api_key,model,credential_id,credential,requests,prompt_tokens,completion_tokens,total_tokens,failed_requests,avg_latency_ms\n",
+    );
+    for row in rows {
+        let id = |v: Option<i32>| v.map(|v| v.to_string()).unwrap_or_default();
+        let avg_latency = if row.requests > 0 { row.latency_ms / row.requests } else { 0 };
+        let fields = [
+            row.day.clone(),
+            id(row.api_token_id),
+            labels.key(row.api_token_id),
+            row.model.clone(),
+            id(row.credential_id),
+            labels.credential(row.credential_id),
+            row.requests.to_string(),
+            row.prompt_tokens.to_string(),
+            row.completion_tokens.to_string(),
+            (row.prompt_tokens + row.completion_tokens).to_string(),
+            row.failed.to_string(),
+            avg_latency.to_string(),
+        ];
+        let line: Vec<String> = fields.iter().map(|f| csv_field(f)).collect();
+        out.push_str(&line.join(","));
+        out.push('\n');
+    }
+    out
+}
+
+/// 按 RFC 4180 转义；以公式字符开头的值加前缀 `'`，防止在电子表格中被执行
+fn csv_field(value: &str) -> String {
+    let value = if value.len() > 1 && value.starts_with(['=', '+', '-', '@']) { format!("'{value}") } else { value.to_string() };
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value
+    }
+}
@@ -1,59 +1,212 @@
+use chrono::Utc;
 use diesel::prelude::*;
-use crate::db::establish_connection;
-use crate::models::{User, NewUser, Credential, NewCredential, ApiToken, NewApiToken};
-use crate::schema::{users, credentials, api_tokens};
+use crate::db::DbPool;
+use crate::models::{User, NewUser, Role, RecoveryCode, NewRecoveryCode, LoginLockout, NewLoginLockout};
+use crate::schema::{users, user_recovery_codes, login_lockouts};
 use anyhow::Result;
-use uuid::Uuid;
 
 // --- User Management ---
-pub fn get_user_by_username(uname: &str) -> Result<User> {
-    let conn = &mut establish_connection();
+pub fn get_user_by_username(pool: &DbPool, uname: &str) -> Result<User> {
+    let conn = &mut pool.get()?;
     users::table.filter(users::username.eq(uname)).first::<User>(conn).map_err(Into::into)
 }
 
-pub fn create_user(uname: &str, pwhash: &str) -> Result<User> {
-    let conn = &mut establish_connection();
-    let new_user = NewUser { username: uname, password_hash: pwhash };
+pub fn get_user(pool: &DbPool, uid: i32) -> Result<Option<User>> {
+    let conn = &mut pool.get()?;
+    Ok(users::table.find(uid).first::<User>(conn).optional()?)
+}
+
+pub fn list_users(pool: &DbPool) -> Result<Vec<User>> {
+    let conn = &mut pool.get()?;
+    Ok(users::table.order(users::username.asc()).load::<User>(conn)?)
+}
+
+/// 新建用户；`must_change` 为真时首次登录后须先修改密码
+pub fn create_user(pool: &DbPool, uname: &str, pwhash: &str, role: Role, must_change: bool) -> Result<User> {
+    let conn = &mut pool.get()?;
+    let new_user = NewUser { username: uname, password_hash: pwhash, must_change_password: must_change, role: role.as_str() };
     diesel::insert_into(users::table).values(&new_user).get_result(conn).map_err(Into::into)
 }
 
-pub fn ensure_admin_exists() -> Result<Option<String>> {
-    if get_user_by_username("admin").is_ok() {
-        return Ok(None);
+/// 重置密码并使已签发的 Token 全部失效，用户不存在时返回错误
+pub fn set_password(pool: &DbPool, uname: &str, pwhash: &str, must_change: bool) -> Result<()> {
+    let conn = &mut pool.get()?;
+    let updated = diesel::update(users::table.filter(users::username.eq(uname)))
+        .set((
+            users::password_hash.eq(pwhash),
+            users::must_change_password.eq(must_change),
+            users::token_version.eq(users::token_version + 1),
+        ))
+        .execute(conn)?;
+    if updated == 0 {
+        anyhow::bail!("User {uname} does not exist");
     }
-    let initial_password = crate::utils::generate_random_password(12);
-    let hashed_password = crate::utils::hash_password(&initial_password)?;
-    create_user("admin", &hashed_password)?;
-    Ok(Some(initial_password))
+    Ok(())
+}
+
+/// 修改角色；不允许移除最后一个启用的 owner
+pub fn set_user_role(pool: &DbPool, uid: i32, role: Role) -> Result<()> {
+    let conn = &mut pool.get()?;
+    conn.transaction(|conn| {
+        if role != Role::Owner {
+            ensure_other_owner(conn, uid)?;
+        }
+        diesel::update(users::table.find(uid)).set(users::role.eq(role.as_str())).execute(conn)?;
+        Ok(())
+    })
 }
 
-// --- Credential Management ---
-pub fn list_credentials() -> Result<Vec<Credential>> {
-    let conn = &mut establish_connection();
-    credentials::table.load::<Credential>(conn).map_err(Into::into)
+/// 停用或重新启用用户；不允许停用最后一个启用的 owner
+pub fn set_user_disabled(pool: &DbPool, uid: i32, disabled: bool) -> Result<()> {
+    let conn = &mut pool.get()?;
+    conn.transaction(|conn| {
+        if disabled {
+            ensure_other_owner(conn, uid)?;
+        }
+        let at = disabled.then(|| Utc::now().naive_utc());
+        diesel::update(users::table.find(uid)).set(users::disabled_at.eq(at)).execute(conn)?;
+        Ok(())
+    })
 }
 
-pub fn create_credential(email_val: &str, token_val: &str) -> Result<Credential> {
-    let conn = &mut establish_connection();
-    let new_credential = NewCredential { email: email_val, token: token_val };
-    diesel::insert_into(credentials::table).values(&new_credential).get_result(conn).map_err(Into::into)
+/// 删除用户；不允许删除最后一个启用的 owner
+pub fn delete_user(pool: &DbPool, uid: i32) -> Result<()> {
+    let conn = &mut pool.get()?;
+    conn.transaction(|conn| {
+        ensure_other_owner(conn, uid)?;
+        diesel::delete(users::table.find(uid)).execute(conn)?;
+        Ok(())
+    })
 }
 
-pub fn delete_credential(cred_id: i32) -> Result<()> {
-    let conn = &mut establish_connection();
-    diesel::delete(credentials::table.find(cred_id)).execute(conn)?;
+/// 除 `uid` 外至少还有一个启用的 owner
+fn ensure_other_owner(conn: &mut SqliteConnection, uid: i32) -> Result<()> {
+    let others: i64 = users::table
+        .filter(users::id.ne(uid))
+        .filter(users::role.eq(Role::Owner.as_str()))
+        .filter(users::disabled_at.is_null())
+        .count()
+        .get_result(conn)?;
+    if others == 0 {
+        anyhow::bail!("至少需要保留一个启用的 owner");
+    }
+    Ok(())
+}
+
+// --- Two-Factor Authentication ---
+
+/// 保存待绑定的 TOTP 密钥（已加密）；已启用两步验证时不覆盖
+pub fn set_pending_totp_secret(pool: &DbPool, uid: i32, encrypted: &str) -> Result<()> {
+    let conn = &mut pool.get()?;
+    diesel::update(users::table.find(uid).filter(users::totp_enabled_at.is_null()))
+        .set((users::totp_secret.eq(Some(encrypted)), users::totp_last_step.eq(None::<i64>)))
+        .execute(conn)?;
     Ok(())
 }
 
-// --- API Token Management ---
-pub fn get_api_token(token_val: &str) -> Result<ApiToken> {
-    let conn = &mut establish_connection();
-    api_tokens::table.filter(api_tokens::token.eq(token_val)).first::<ApiToken>(conn).map_err(Into::into)
+/// 完成绑定：启用两步验证并替换恢复码
+pub fn enable_totp(pool: &DbPool, uid: i32, step: i64, code_hashes: Vec<String>) -> Result<()> {
+    let conn = &mut pool.get()?;
+    conn.transaction(|conn| {
+        diesel::update(users::table.find(uid))
+            .set((users::totp_enabled_at.eq(Some(Utc::now().naive_utc())), users::totp_last_step.eq(Some(step))))
+            .execute(conn)?;
+        replace_recovery_codes_in(conn, uid, code_hashes)
+    })
 }
 
-pub fn create_api_token() -> Result<ApiToken> {
-    let conn = &mut establish_connection();
-    let new_token_value = Uuid::new_v4().to_string();
-    let new_api_token = NewApiToken { token: new_token_value };
-    diesel::insert_into(api_tokens::table).values(&new_api_token).get_result(conn).map_err(Into::into)
+/// 关闭两步验证并删除恢复码
+pub fn disable_totp(pool: &DbPool, uid: i32) -> Result<()> {
+    let conn = &mut pool.get()?;
+    conn.transaction(|conn| {
+        diesel::update(users::table.find(uid))
+            .set((
+                users::totp_secret.eq(None::<String>),
+                users::totp_enabled_at.eq(None::<chrono::NaiveDateTime>),
+                users::totp_last_step.eq(None::<i64>),
+            ))
+            .execute(conn)?;
+        diesel::delete(user_recovery_codes::table.filter(user_recovery_codes::user_id.eq(uid))).execute(conn)?;
+        Ok(())
+    })
+}
+
+/// 记录已使用的时间步；若同一或更晚的时间步已被使用则返回 false（验证码重放）
+pub fn record_totp_step(pool: &DbPool, uid: i32, step: i64) -> Result<bool> {
+    let conn = &mut pool.get()?;
+    let updated = diesel::update(
+        users::table
+            .find(uid)
+            .filter(users::totp_last_step.is_null().or(users::totp_last_step.lt(step))),
+    )
+    .set(users::totp_last_step.eq(Some(step)))
+    .execute(conn)?;
+    Ok(updated == 1)
+}
+
+pub fn replace_recovery_codes(pool: &DbPool, uid: i32, code_hashes: Vec<String>) -> Result<()> {
+    let conn = &mut pool.get()?;
+    conn.transaction(|conn| replace_recovery_codes_in(conn, uid, code_hashes))
+}
+
+fn replace_recovery_codes_in(conn: &mut SqliteConnection, uid: i32, code_hashes: Vec<String>) -> Result<()> {
+    diesel::delete(user_recovery_codes::table.filter(user_recovery_codes::user_id.eq(uid))).execute(conn)?;
+    let rows: Vec<NewRecoveryCode> = code_hashes.into_iter().map(|code_hash| NewRecoveryCode { user_id: uid, code_hash }).collect();
+    diesel::insert_into(user_recovery_codes::table).values(&rows).execute(conn)?;
+    Ok(())
+}
+
+/// 尚未使用的恢复码
+pub fn unused_recovery_codes(pool: &DbPool, uid: i32) -> Result<Vec<RecoveryCode>> {
+    let conn = &mut pool.get()?;
+    Ok(user_recovery_codes::table
+        .filter(user_recovery_codes::user_id.eq(uid))
+        .filter(user_recovery_codes::used_at.is_null())
+        .load::<RecoveryCode>(conn)?)
+}
+
+/// 将恢复码标记为已使用；已被并发使用时返回 false
+pub fn use_recovery_code(pool: &DbPool, code_id: i32) -> Result<bool> {
+    let conn = &mut pool.get()?;
+    let updated = diesel::update(user_recovery_codes::table.find(code_id).filter(user_recovery_codes::used_at.is_null()))
+        .set(user_recovery_codes::used_at.eq(Some(Utc::now().naive_utc())))
+        .execute(conn)?;
+    Ok(updated == 1)
+}
+
+/// 记录一次登录锁定
+pub fn record_lockout(pool: &DbPool, lockout: &NewLoginLockout) -> Result<()> {
+    let conn = &mut pool.get()?;
+    diesel::insert_into(login_lockouts::table).values(lockout).execute(conn)?;
+    Ok(())
+}
+
+/// 最近的登录锁定记录，新的在前
+pub fn recent_lockouts(pool: &DbPool, limit: i64) -> Result<Vec<LoginLockout>> {
+    let conn = &mut pool.get()?;
+    Ok(login_lockouts::table
+        .order(login_lockouts::id.desc())
+        .limit(limit)
+        .load::<LoginLockout>(conn)?)
+}
+
+/// 标记锁定已被手动解除，返回该记录；记录不存在时返回 None
+pub fn clear_lockout(pool: &DbPool, lockout_id: i32) -> Result<Option<LoginLockout>> {
+    let conn = &mut pool.get()?;
+    diesel::update(login_lockouts::table.find(lockout_id).filter(login_lockouts::cleared_at.is_null()))
+        .set(login_lockouts::cleared_at.eq(Some(Utc::now().naive_utc())))
+        .execute(conn)?;
+    Ok(login_lockouts::table.find(lockout_id).first::<LoginLockout>(conn).optional()?)
+}
+
+/// 尚无任何用户时创建初始 owner `admin`，返回其随机密码
+pub fn ensure_admin_exists(pool: &DbPool) -> Result<Option<String>> {
+    let existing: i64 = users::table.count().get_result(&mut pool.get()?)?;
+    if existing > 0 {
+        return Ok(None);
+    }
+    let initial_password = crate::utils::generate_random_password(12);
+    let hashed_password = crate::utils::hash_password(&initial_password)?;
+    create_user(pool, "admin", &hashed_password, Role::Owner, true)?;
+    Ok(Some(initial_password))
 }
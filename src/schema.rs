@@ -3,6 +3,22 @@ diesel::table! {
         id -> Integer,
         username -> Text,
         password_hash -> Text,
+        must_change_password -> Bool,
+        role -> Text,
+        disabled_at -> Nullable<Timestamp>,
+        totp_secret -> Nullable<Text>,
+        totp_enabled_at -> Nullable<Timestamp>,
+        totp_last_step -> Nullable<BigInt>,
+        token_version -> Integer,
+    }
+}
+
+diesel::table! {
+    user_recovery_codes (id) {
+        id -> Integer,
+        user_id -> Integer,
+        code_hash -> Text,
+        used_at -> Nullable<Timestamp>,
     }
 }
 
@@ -11,19 +27,86 @@ diesel::table! {
         id -> Integer,
         email -> Text,
         token -> Text,
+        weight -> Integer,
+        status -> Text,
+        cooldown_until -> Nullable<Timestamp>,
+        failure_count -> Integer,
+        last_error -> Nullable<Text>,
+        last_checked_at -> Nullable<Timestamp>,
+        last_check_latency_ms -> Nullable<Integer>,
+        last_check_result -> Nullable<Text>,
     }
 }
 
 diesel::table! {
     api_tokens (id) {
         id -> Integer,
-        token -> Text,
+        legacy_token -> Nullable<Text>,
+        token_prefix -> Text,
+        token_hash -> Text,
+        created_at -> Nullable<Timestamp>,
+        name -> Text,
+        owner -> Text,
+        last_used_at -> Nullable<Timestamp>,
+        expires_at -> Nullable<Timestamp>,
+        revoked_at -> Nullable<Timestamp>,
+        rpm_limit -> Nullable<Integer>,
+        tpm_limit -> Nullable<Integer>,
+        max_concurrent -> Nullable<Integer>,
+    }
+}
+
+diesel::table! {
+    catalog_models (id) {
+        id -> Integer,
+        alias -> Text,
+        upstream_model -> Text,
+        owned_by -> Text,
+        context_window -> Nullable<Integer>,
+        created -> BigInt,
     }
 }
 
+diesel::table! {
+    request_log (id) {
+        id -> Integer,
+        created_at -> Timestamp,
+        api_token_id -> Nullable<Integer>,
+        principal -> Text,
+        credential_id -> Nullable<Integer>,
+        model -> Text,
+        prompt_tokens -> Integer,
+        completion_tokens -> Integer,
+        latency_ms -> Integer,
+        upstream_status -> Nullable<Integer>,
+        stream -> Bool,
+        attempts -> Integer,
+        error -> Nullable<Text>,
+    }
+}
+
+diesel::table! {
+    login_lockouts (id) {
+        id -> Integer,
+        created_at -> Timestamp,
+        scope -> Text,
+        subject -> Text,
+        ip -> Text,
+        failures -> Integer,
+        locked_until -> Timestamp,
+        cleared_at -> Nullable<Timestamp>,
+    }
+}
+
+diesel::joinable!(user_recovery_codes -> users (user_id));
+
 diesel::allow_tables_to_appear_in_same_query!(
     users,
+    user_recovery_codes,
     credentials,
     api_tokens,
+    catalog_models,
+    request_log,
+    login_lockouts,
 );
 
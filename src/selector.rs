@@ -0,0 +1,188 @@
+//! 凭据选择策略：决定每次请求依次尝试凭据池中凭据的顺序
+
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use rand::Rng;
+
+use crate::models::Credential;
+use crate::settings::UpstreamSettings;
+
+/// 单个凭据的运行时使用情况
+#[derive(Default, Clone, Copy)]
+pub struct CredentialUsage {
+    pub in_flight: usize,
+    pub last_used: Option<Instant>,
+}
+
+/// 所有凭据的运行时使用情况（进程内，不落库）
+#[derive(Default)]
+pub struct UsageStats {
+    inner: Mutex<HashMap<i32, CredentialUsage>>,
+}
+
+impl UsageStats {
+    pub fn get(&self, id: i32) -> CredentialUsage {
+        self.inner.lock().unwrap().get(&id).copied().unwrap_or_default()
+    }
+
+    fn begin(&self, id: i32) {
+        let mut map = self.inner.lock().unwrap();
+        let usage = map.entry(id).or_default();
+        usage.in_flight += 1;
+        usage.last_used = Some(Instant::now());
+    }
+
+    fn end(&self, id: i32) {
+        if let Some(usage) = self.inner.lock().unwrap().get_mut(&id) {
+            usage.in_flight = usage.in_flight.saturating_sub(1);
+        }
+    }
+}
+
+/// 凭据选择策略
+pub trait CredentialSelector: Send + Sync {
+    /// 返回本次请求的尝试顺序：第一个为首选，其余用于故障转移
+    fn order(&self, credentials: Vec<Credential>, stats: &UsageStats) -> Vec<Credential>;
+}
+
+/// 轮询：每次请求从下一个凭据开始
+#[derive(Default)]
+pub struct RoundRobin {
+    next: AtomicUsize,
+}
+
+impl CredentialSelector for RoundRobin {
+    fn order(&self, mut credentials: Vec<Credential>, _stats: &UsageStats) -> Vec<Credential> {
+        if credentials.is_empty() {
+            return credentials;
+        }
+        credentials.sort_by_key(|c| c.id);
+        let start = self.next.fetch_add(1, Ordering::Relaxed) % credentials.len();
+        credentials.rotate_left(start);
+        credentials
+    }
+}
+
+/// 加权随机：按 `weight` 做不放回抽样（Efraimidis–Spirakis）
+pub struct WeightedRandom;
+
+impl CredentialSelector for WeightedRandom {
+    fn order(&self, credentials: Vec<Credential>, _stats: &UsageStats) -> Vec<Credential> {
+        let mut rng = rand::thread_rng();
+        let mut keyed: Vec<(f64, Credential)> = credentials
+            .into_iter()
+            .map(|c| {
+                let u: f64 = rng.gen_range(f64::EPSILON..1.0);
+                (u.powf(1.0 / c.weight.max(1) as f64), c)
+            })
+            .collect();
+        keyed.sort_by(|a, b| b.0.total_cmp(&a.0));
+        keyed.into_iter().map(|(_, c)| c).collect()
+    }
+}
+
+/// 最久未使用优先
+pub struct LeastRecentlyUsed;
+
+impl CredentialSelector for LeastRecentlyUsed {
+    fn order(&self, mut credentials: Vec<Credential>, stats: &UsageStats) -> Vec<Credential> {
+        // 从未使用过的凭据（None）排在最前
+        credentials.sort_by_key(|c| (stats.get(c.id).last_used, c.id));
+        credentials
+    }
+}
+
+/// 当前并发请求数最少优先，并列时取最久未使用者
+pub struct LeastInFlight;
+
+impl CredentialSelector for LeastInFlight {
+    fn order(&self, mut credentials: Vec<Credential>, stats: &UsageStats) -> Vec<Credential> {
+        credentials.sort_by_key(|c| {
+            let usage = stats.get(c.id);
+            (usage.in_flight, usage.last_used, c.id)
+        });
+        credentials
+    }
+}
+
+/// 可配置的策略名称（配置项 `upstream.credential_strategy`）
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Strategy {
+    RoundRobin,
+    WeightedRandom,
+    LeastRecentlyUsed,
+    LeastInFlight,
+}
+
+impl FromStr for Strategy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().replace('-', "_").as_str() {
+            "round_robin" => Ok(Strategy::RoundRobin),
+            "weighted_random" | "weighted" => Ok(Strategy::WeightedRandom),
+            "least_recently_used" | "lru" => Ok(Strategy::LeastRecentlyUsed),
+            "least_in_flight" => Ok(Strategy::LeastInFlight),
+            other => Err(anyhow::anyhow!("Unknown credential strategy: {other}")),
+        }
+    }
+}
+
+impl Strategy {
+    fn selector(self) -> Box<dyn CredentialSelector> {
+        match self {
+            Strategy::RoundRobin => Box::<RoundRobin>::default(),
+            Strategy::WeightedRandom => Box::new(WeightedRandom),
+            Strategy::LeastRecentlyUsed => Box::new(LeastRecentlyUsed),
+            Strategy::LeastInFlight => Box::new(LeastInFlight),
+        }
+    }
+}
+
+/// 凭据池：选择策略 + 运行时使用情况，通过 `web::Data` 共享
+pub struct CredentialPool {
+    selector: Box<dyn CredentialSelector>,
+    stats: Arc<UsageStats>,
+}
+
+impl CredentialPool {
+    pub fn new(strategy: Strategy) -> Self {
+        Self { selector: strategy.selector(), stats: Arc::new(UsageStats::default()) }
+    }
+
+    /// 按配置 `upstream.credential_strategy` 选择策略，无法识别时使用轮询
+    pub fn from_settings(upstream: &UpstreamSettings) -> Self {
+        let strategy = upstream.credential_strategy.parse().unwrap_or_else(|e| {
+            log::warn!("{e}, falling back to round_robin");
+            Strategy::RoundRobin
+        });
+        log::info!("Credential selection strategy: {:?}", strategy);
+        Self::new(strategy)
+    }
+
+    pub fn order(&self, credentials: Vec<Credential>) -> Vec<Credential> {
+        self.selector.order(credentials, &self.stats)
+    }
+
+    /// 标记凭据开始处理一个请求，返回的租约在 drop 时释放
+    pub fn acquire(&self, id: i32) -> CredentialLease {
+        self.stats.begin(id);
+        CredentialLease { stats: self.stats.clone(), id }
+    }
+}
+
+/// 凭据使用租约，drop 时减少该凭据的并发计数
+pub struct CredentialLease {
+    stats: Arc<UsageStats>,
+    id: i32,
+}
+
+impl Drop for CredentialLease {
+    fn drop(&mut self) {
+        self.stats.end(self.id);
+    }
+}
@@ -1,66 +1,404 @@
 //! 高层服务函数，封装数据库访问逻辑，供 handler 调用
 
 use anyhow::Result;
+use chrono::{Duration, NaiveDateTime, Utc};
 use diesel::prelude::*;
-use uuid::Uuid;
 
-use crate::{db::establish_connection, models::{Credential, NewCredential, ApiToken, NewApiToken}};
+use crate::crypto::{self, MasterKey};
+use crate::utils;
+use crate::{db::DbPool, models::{Credential, NewCredential, ApiToken, NewApiToken, KeyLimits, CatalogModel, NewCatalogModel, NewRequestLog, UsageRow, CREDENTIAL_ACTIVE, CREDENTIAL_DISABLED}};
 
 // ----------------- Credential -----------------
 
-pub fn list_credentials() -> Result<Vec<Credential>> {
+pub fn list_credentials(pool: &DbPool) -> Result<Vec<Credential>> {
     use crate::schema::credentials::dsl::*;
-    let conn = &mut establish_connection();
+    let conn = &mut pool.get()?;
     Ok(credentials.load::<Credential>(conn)?)
 }
 
-pub fn create_credential(email_str: &str, token_str: &str) -> Result<()> {
+/// 新增凭据，Token 以主密钥信封加密后落库
+pub fn create_credential(pool: &DbPool, master_key: &MasterKey, email_str: &str, token_str: &str, weight_val: i32) -> Result<()> {
     use crate::schema::credentials::dsl::*;
-    let conn = &mut establish_connection();
-    let new = NewCredential { email: email_str.into(), token: token_str.into() };
+    let conn = &mut pool.get()?;
+    let encrypted = master_key.encrypt(token_str)?;
+    let new = NewCredential { email: email_str, token: &encrypted, weight: weight_val.max(1) };
     diesel::insert_into(credentials).values(&new).execute(conn)?;
     Ok(())
 }
 
-pub fn remove_credential(cid: i32) -> Result<()> {
+/// 加密引入加密存储前遗留的明文 Token，返回处理条数
+pub fn encrypt_plaintext_credentials(pool: &DbPool, master_key: &MasterKey) -> Result<usize> {
     use crate::schema::credentials::dsl::*;
-    let conn = &mut establish_connection();
+    let conn = &mut pool.get()?;
+    let rows: Vec<(i32, String)> = credentials.select((id, token)).load(conn)?;
+    let mut count = 0;
+    for (cid, raw) in rows.iter().filter(|(_, t)| !crypto::is_encrypted(t)) {
+        let encrypted = master_key.encrypt(raw)?;
+        diesel::update(credentials.filter(id.eq(cid))).set(token.eq(encrypted)).execute(conn)?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// 用新主密钥重新包裹所有凭据与两步验证密钥的数据密钥；任一记录失败则整体回滚。
+/// 返回重新加密的凭据数与两步验证密钥数
+pub fn rotate_master_key(pool: &DbPool, old_key: &MasterKey, new_key: &MasterKey) -> Result<(usize, usize)> {
+    use crate::schema::{credentials, users};
+    let conn = &mut pool.get()?;
+    conn.transaction(|conn| {
+        let rows: Vec<(i32, String)> = credentials::table.select((credentials::id, credentials::token)).load(conn)?;
+        for (cid, stored) in &rows {
+            let rewrapped = old_key.rewrap(stored, new_key)?;
+            diesel::update(credentials::table.find(cid)).set(credentials::token.eq(rewrapped)).execute(conn)?;
+        }
+        // 两步验证密钥（含尚未确认的）同样由主密钥加密，遗漏会导致轮换后无法登录
+        let secrets: Vec<(i32, Option<String>)> = users::table
+            .filter(users::totp_secret.is_not_null())
+            .select((users::id, users::totp_secret))
+            .load(conn)?;
+        for (uid, stored) in &secrets {
+            let Some(stored) = stored else { continue };
+            let rewrapped = old_key.rewrap(stored, new_key)?;
+            diesel::update(users::table.find(uid)).set(users::totp_secret.eq(Some(rewrapped))).execute(conn)?;
+        }
+        Ok((rows.len(), secrets.len()))
+    })
+}
+
+pub fn remove_credential(pool: &DbPool, cid: i32) -> Result<()> {
+    use crate::schema::credentials::dsl::*;
+    let conn = &mut pool.get()?;
     diesel::delete(credentials.filter(id.eq(cid))).execute(conn)?;
     Ok(())
 }
 
+/// 可参与选择的凭据：状态为 active 且不在冷却期
+pub fn list_available_credentials(pool: &DbPool) -> Result<Vec<Credential>> {
+    use crate::schema::credentials::dsl::*;
+    let conn = &mut pool.get()?;
+    let now = Utc::now().naive_utc();
+    Ok(credentials
+        .filter(status.eq(CREDENTIAL_ACTIVE))
+        .filter(cooldown_until.is_null().or(cooldown_until.le(now)))
+        .load::<Credential>(conn)?)
+}
+
+/// 上游返回 429：进入冷却期
+pub fn cool_down_credential(pool: &DbPool, cid: i32, duration: Duration, error: &str) -> Result<()> {
+    use crate::schema::credentials::dsl::*;
+    let conn = &mut pool.get()?;
+    let until = Utc::now().naive_utc() + duration;
+    diesel::update(credentials.filter(id.eq(cid)))
+        .set((cooldown_until.eq(Some(until)), failure_count.eq(failure_count + 1), last_error.eq(Some(error))))
+        .execute(conn)?;
+    Ok(())
+}
+
+/// 上游返回 401/403：禁用凭据，需要管理员重新启用
+pub fn disable_credential(pool: &DbPool, cid: i32, error: &str) -> Result<()> {
+    use crate::schema::credentials::dsl::*;
+    let conn = &mut pool.get()?;
+    diesel::update(credentials.filter(id.eq(cid)))
+        .set((status.eq(CREDENTIAL_DISABLED), failure_count.eq(failure_count + 1), last_error.eq(Some(error))))
+        .execute(conn)?;
+    Ok(())
+}
+
+/// 其他失败（5xx、网络错误）：只记录，不影响选择
+pub fn record_credential_failure(pool: &DbPool, cid: i32, error: &str) -> Result<()> {
+    use crate::schema::credentials::dsl::*;
+    let conn = &mut pool.get()?;
+    diesel::update(credentials.filter(id.eq(cid)))
+        .set((failure_count.eq(failure_count + 1), last_error.eq(Some(error))))
+        .execute(conn)?;
+    Ok(())
+}
+
+/// 请求成功：清除失败计数与冷却状态
+pub fn record_credential_success(pool: &DbPool, cid: i32) -> Result<()> {
+    use crate::schema::credentials::dsl::*;
+    let conn = &mut pool.get()?;
+    diesel::update(credentials.filter(id.eq(cid)).filter(failure_count.ne(0).or(cooldown_until.is_not_null())))
+        .set((cooldown_until.eq(None::<NaiveDateTime>), failure_count.eq(0), last_error.eq(None::<String>)))
+        .execute(conn)?;
+    Ok(())
+}
+
+/// 健康检查通过：被禁用的凭据恢复可用；失败计数与冷却保持不变，由真实请求的结果决定
+pub fn record_probe_success(pool: &DbPool, cid: i32) -> Result<()> {
+    use crate::schema::credentials::dsl::*;
+    let conn = &mut pool.get()?;
+    diesel::update(credentials.filter(id.eq(cid)).filter(status.ne(CREDENTIAL_ACTIVE)))
+        .set(status.eq(CREDENTIAL_ACTIVE))
+        .execute(conn)?;
+    Ok(())
+}
+
+/// 记录一次健康检查的时间、耗时与结果
+pub fn record_probe(pool: &DbPool, cid: i32, latency_ms: i32, result: &str) -> Result<()> {
+    use crate::schema::credentials::dsl::*;
+    let conn = &mut pool.get()?;
+    diesel::update(credentials.filter(id.eq(cid)))
+        .set((
+            last_checked_at.eq(Some(Utc::now().naive_utc())),
+            last_check_latency_ms.eq(Some(latency_ms)),
+            last_check_result.eq(Some(result)),
+        ))
+        .execute(conn)?;
+    Ok(())
+}
+
+pub fn get_credential(pool: &DbPool, cid: i32) -> Result<Option<Credential>> {
+    use crate::schema::credentials::dsl::*;
+    let conn = &mut pool.get()?;
+    Ok(credentials.filter(id.eq(cid)).first::<Credential>(conn).optional()?)
+}
+
+/// 管理员手动恢复：重新启用并清除冷却
+pub fn enable_credential(pool: &DbPool, cid: i32) -> Result<()> {
+    use crate::schema::credentials::dsl::*;
+    let conn = &mut pool.get()?;
+    diesel::update(credentials.filter(id.eq(cid)))
+        .set((status.eq(CREDENTIAL_ACTIVE), cooldown_until.eq(None::<NaiveDateTime>), failure_count.eq(0), last_error.eq(None::<String>)))
+        .execute(conn)?;
+    Ok(())
+}
+
 // ----------------- API Token -----------------
-pub fn current_api_token() -> Result<Option<ApiToken>> {
+
+/// `last_used_at` 的最小刷新间隔，避免每个请求都写库
+const LAST_USED_RESOLUTION_SECS: i64 = 60;
+
+/// 列出所有 API Key（含已吊销、已过期），最新的在前
+pub fn list_api_tokens(pool: &DbPool) -> Result<Vec<ApiToken>> {
     use crate::schema::api_tokens::dsl::*;
-    let conn = &mut establish_connection();
-    Ok(api_tokens.order(id.desc()).first::<ApiToken>(conn).optional()?)
+    let conn = &mut pool.get()?;
+    Ok(api_tokens.order(id.desc()).load::<ApiToken>(conn)?)
 }
 
-pub fn generate_api_token() -> Result<String> {
+/// 生成新的具名 API Key，只保存哈希与可见前缀；返回的明文仅此一次可见
+pub fn generate_api_token(pool: &DbPool, secret: &str, name_str: &str, owner_str: &str, expires: Option<NaiveDateTime>, limits: KeyLimits) -> Result<String> {
     use crate::schema::api_tokens::dsl::*;
-    let conn = &mut establish_connection();
-    let new_value = Uuid::new_v4().to_string();
-    let new_row = NewApiToken { token: new_value.clone() };
+    let conn = &mut pool.get()?;
+    let new_value = utils::generate_api_key();
+    let prefix = utils::api_key_prefix(&new_value);
+    let (rpm, tpm, concurrent) = limits.columns();
+    let new_row = NewApiToken {
+        token_prefix: &prefix,
+        token_hash: utils::hash_api_key(secret, &new_value),
+        name: name_str,
+        owner: owner_str,
+        expires_at: expires,
+        rpm_limit: rpm,
+        tpm_limit: tpm,
+        max_concurrent: concurrent,
+    };
     diesel::insert_into(api_tokens).values(&new_row).execute(conn)?;
     Ok(new_value)
 }
 
-pub fn revoke_api_token(token_id: i32) -> Result<()> {
+/// 将引入哈希存储前遗留的明文 Key 转为哈希，返回处理条数
+pub fn hash_legacy_api_tokens(pool: &DbPool, secret: &str) -> Result<usize> {
+    use crate::schema::api_tokens::dsl::*;
+    let conn = &mut pool.get()?;
+    let legacy: Vec<(i32, Option<String>)> = api_tokens
+        .filter(legacy_token.is_not_null())
+        .select((id, legacy_token))
+        .load(conn)?;
+    for (tid, raw) in &legacy {
+        let raw = raw.as_deref().unwrap_or_default();
+        diesel::update(api_tokens.filter(id.eq(tid)))
+            .set((
+                token_prefix.eq(utils::api_key_prefix(raw)),
+                token_hash.eq(utils::hash_api_key(secret, raw)),
+                legacy_token.eq(None::<String>),
+            ))
+            .execute(conn)?;
+    }
+    Ok(legacy.len())
+}
+
+/// 修改名称、所有者、过期时间与限流配置
+pub fn update_api_token(pool: &DbPool, token_id: i32, name_str: &str, owner_str: &str, expires: Option<NaiveDateTime>, limits: KeyLimits) -> Result<()> {
     use crate::schema::api_tokens::dsl::*;
-    let conn = &mut establish_connection();
-    diesel::delete(api_tokens.filter(id.eq(token_id))).execute(conn)?;
+    let conn = &mut pool.get()?;
+    let (rpm, tpm, concurrent) = limits.columns();
+    diesel::update(api_tokens.filter(id.eq(token_id)))
+        .set((
+            name.eq(name_str),
+            owner.eq(owner_str),
+            expires_at.eq(expires),
+            rpm_limit.eq(rpm),
+            tpm_limit.eq(tpm),
+            max_concurrent.eq(concurrent),
+        ))
+        .execute(conn)?;
     Ok(())
 }
 
-pub fn validate_api_token(token_str: &str) -> Result<()> {
+/// 吊销 API Key：保留记录以便审计，仅标记吊销时间
+pub fn revoke_api_token(pool: &DbPool, token_id: i32) -> Result<()> {
     use crate::schema::api_tokens::dsl::*;
-    let conn = &mut establish_connection();
-    let token_found = diesel::select(diesel::dsl::exists(api_tokens.filter(token.eq(token_str))))
-        .get_result::<bool>(conn)?;
-
-    if token_found {
-        Ok(())
-    } else {
-        Err(anyhow::anyhow!("Invalid API token"))
+    let conn = &mut pool.get()?;
+    diesel::update(api_tokens.filter(id.eq(token_id)).filter(revoked_at.is_null()))
+        .set(revoked_at.eq(Some(Utc::now().naive_utc())))
+        .execute(conn)?;
+    Ok(())
+}
+
+/// 校验 API Token（未吊销、未过期），成功时刷新最近使用时间并返回对应记录
+pub fn validate_api_token(pool: &DbPool, secret: &str, token_str: &str) -> Result<ApiToken> {
+    use crate::schema::api_tokens::dsl::*;
+    let conn = &mut pool.get()?;
+    let now = Utc::now().naive_utc();
+    // 先按前缀缩小范围，再逐个以常量时间校验哈希
+    let found = api_tokens
+        .filter(token_prefix.eq(utils::api_key_prefix(token_str)))
+        .load::<ApiToken>(conn)?
+        .into_iter()
+        .find(|t| utils::verify_api_key(secret, token_str, &t.token_hash))
+        .filter(|t| t.is_usable(now))
+        .ok_or_else(|| anyhow::anyhow!("Invalid API token"))?;
+
+    let stale = now - Duration::seconds(LAST_USED_RESOLUTION_SECS);
+    diesel::update(api_tokens.filter(id.eq(found.id)).filter(last_used_at.is_null().or(last_used_at.lt(stale))))
+        .set(last_used_at.eq(Some(now)))
+        .execute(conn)?;
+    Ok(found)
+}
+
+// ----------------- Model Catalog -----------------
+
+/// 首次启动时写入的默认模型别名：(别名, Atlassian 模型 ID, 所有者, 上下文窗口)
+const DEFAULT_MODELS: &[(&str, &str, &str, i32)] = &[
+    ("gpt-4o", "gpt-4o-2024-08-06", "openai", 128_000),
+    ("gpt-4o-mini", "gpt-4o-mini-2024-07-18", "openai", 128_000),
+    ("claude-3-5-sonnet", "claude-3-5-sonnet-v2@20241022", "anthropic", 200_000),
+];
+
+pub fn list_models(pool: &DbPool) -> Result<Vec<CatalogModel>> {
+    use crate::schema::catalog_models::dsl::*;
+    let conn = &mut pool.get()?;
+    Ok(catalog_models.order(alias.asc()).load::<CatalogModel>(conn)?)
+}
+
+/// 按公开别名查找模型，未登记时返回 `None`
+pub fn find_model(pool: &DbPool, alias_str: &str) -> Result<Option<CatalogModel>> {
+    use crate::schema::catalog_models::dsl::*;
+    let conn = &mut pool.get()?;
+    Ok(catalog_models.filter(alias.eq(alias_str)).first::<CatalogModel>(conn).optional()?)
+}
+
+pub fn create_model(pool: &DbPool, alias_str: &str, upstream_str: &str, owner_str: &str, context: Option<i32>) -> Result<()> {
+    use crate::schema::catalog_models::dsl::*;
+    let conn = &mut pool.get()?;
+    let new = NewCatalogModel {
+        alias: alias_str,
+        upstream_model: upstream_str,
+        owned_by: owner_str,
+        context_window: context,
+        created: Utc::now().timestamp(),
+    };
+    diesel::insert_into(catalog_models).values(&new).execute(conn)?;
+    Ok(())
+}
+
+pub fn remove_model(pool: &DbPool, mid: i32) -> Result<()> {
+    use crate::schema::catalog_models::dsl::*;
+    let conn = &mut pool.get()?;
+    diesel::delete(catalog_models.filter(id.eq(mid))).execute(conn)?;
+    Ok(())
+}
+
+/// 模型目录为空时写入默认别名，返回写入条数
+pub fn seed_default_models(pool: &DbPool) -> Result<usize> {
+    use crate::schema::catalog_models::dsl::*;
+    let conn = &mut pool.get()?;
+    let existing: i64 = catalog_models.count().get_result(conn)?;
+    if existing > 0 {
+        return Ok(0);
+    }
+    let now = Utc::now().timestamp();
+    let rows: Vec<NewCatalogModel> = DEFAULT_MODELS
+        .iter()
+        .map(|(a, u, o, c)| NewCatalogModel { alias: a, upstream_model: u, owned_by: o, context_window: Some(*c), created: now })
+        .collect();
+    Ok(diesel::insert_into(catalog_models).values(&rows).execute(conn)?)
+}
+
+// ----------------- Request Log -----------------
+
+/// 批量写入请求记账记录
+pub fn insert_request_logs(pool: &DbPool, entries: &[NewRequestLog]) -> Result<usize> {
+    use crate::schema::request_log::dsl::*;
+    let conn = &mut pool.get()?;
+    Ok(diesel::insert_into(request_log).values(entries).execute(conn)?)
+}
+
+/// 统计 `[from, to)` 时间段内的用量，按天、API Key、模型与凭据分组
+pub fn usage_rows(pool: &DbPool, from: NaiveDateTime, to: NaiveDateTime) -> Result<Vec<UsageRow>> {
+    use diesel::sql_types::Timestamp;
+    let conn = &mut pool.get()?;
+    let rows = diesel::sql_query(
+        "SELECT date(created_at) AS day, api_token_id, model, credential_id, \
+                COUNT(*) AS requests, \
+                COALESCE(SUM(prompt_tokens), 0) AS prompt_tokens, \
+                COALESCE(SUM(completion_tokens), 0) AS completion_tokens, \
+                COALESCE(SUM(error IS NOT NULL), 0) AS failed, \
+                COALESCE(SUM(latency_ms), 0) AS latency_ms \
+         FROM request_log \
+         WHERE created_at >= ? AND created_at < ? \
+         GROUP BY day, api_token_id, model, credential_id \
+         ORDER BY day, api_token_id, model, credential_id",
+    )
+    .bind::<Timestamp, _>(from)
+    .bind::<Timestamp, _>(to)
+    .load::<UsageRow>(conn)?;
+    Ok(rows)
+}
+
+#[cfg(test)]
+mod tests {
+    use base64::{engine::general_purpose::STANDARD as B64, Engine};
+
+    use super::*;
+    use crate::models::Role;
+    use crate::{auth, db, repository, totp};
+
+    fn key(byte: u8) -> MasterKey {
+        MasterKey::from_base64(&B64.encode([byte; 32])).unwrap()
+    }
+
+    #[test]
+    fn rotating_master_key_keeps_totp_usable() {
+        let pool = db::test_pool();
+        let (old_key, new_key) = (key(1), key(2));
+        create_credential(&pool, &old_key, "a@example.com", "secret-token", 1).unwrap();
+        let user = repository::create_user(&pool, "alice", "unused", Role::Owner, false).unwrap();
+        let secret = totp::generate_secret();
+        repository::set_pending_totp_secret(&pool, user.id, &old_key.encrypt(&secret).unwrap()).unwrap();
+        repository::enable_totp(&pool, user.id, 0, Vec::new()).unwrap();
+
+        assert_eq!(rotate_master_key(&pool, &old_key, &new_key).unwrap(), (1, 1));
+
+        let credential = &list_credentials(&pool).unwrap()[0];
+        assert_eq!(new_key.decrypt(&credential.token).unwrap(), "secret-token");
+        let user = repository::get_user(&pool, user.id).unwrap().unwrap();
+        let code = totp::code_at(&secret, Utc::now().timestamp() as u64).unwrap();
+        assert!(auth::verify_second_factor(&pool, &new_key, &user, &code).unwrap());
+        assert!(old_key.decrypt(user.totp_secret.as_deref().unwrap()).is_err());
+    }
+
+    #[test]
+    fn setting_password_invalidates_issued_tokens() {
+        let pool = db::test_pool();
+        let user = repository::create_user(&pool, "alice", "unused", Role::Owner, false).unwrap();
+        let issued = user.token_version;
+        assert!(user.accepts_token(issued));
+
+        repository::set_password(&pool, "alice", "changed", false).unwrap();
+        let user = repository::get_user(&pool, user.id).unwrap().unwrap();
+        assert!(!user.accepts_token(issued));
+        assert!(user.accepts_token(user.token_version));
     }
 }
@@ -0,0 +1,481 @@
+//! 分层配置：内置默认值 < TOML 配置文件 < 旧版环境变量 < `APP_` 前缀环境变量 < 命令行参数
+
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::io::Write;
+use std::net::IpAddr;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use clap::Parser;
+use config::{Config, Environment, File};
+use serde::{de, Deserialize, Deserializer};
+
+use crate::cli::Command;
+use crate::utils;
+
+/// 未指定 `--config` 时尝试读取的配置文件（不存在则跳过）
+const DEFAULT_CONFIG_FILE: &str = "config.toml";
+
+/// 旧版环境变量名与配置键的对应关系，保证已有部署无需修改即可升级
+const LEGACY_ENV: &[(&str, &str)] = &[
+    ("DATABASE_URL", "database.url"),
+    ("DATABASE_POOL_SIZE", "database.pool_size"),
+    ("JWT_SECRET", "auth.jwt_secret"),
+    ("JWT_SECRET_FILE", "auth.jwt_secret_file"),
+    ("JWT_ACTIVE_KID", "auth.jwt_active_kid"),
+    ("DEV_MODE", "auth.dev_mode"),
+    ("API_KEY_SECRET", "auth.api_key_secret"),
+    ("API_KEY_SECRET_FILE", "auth.api_key_secret_file"),
+    ("METRICS_TOKEN", "auth.metrics_token"),
+    ("CREDENTIAL_MASTER_KEY", "auth.master_key"),
+    ("CREDENTIAL_MASTER_KEY_FILE", "auth.master_key_file"),
+    ("CREDENTIAL_MASTER_KEY_NEW", "auth.new_master_key"),
+    ("CREDENTIAL_MASTER_KEY_NEW_FILE", "auth.new_master_key_file"),
+    ("CREDENTIAL_STRATEGY", "upstream.credential_strategy"),
+    ("PROBE_INTERVAL_SECS", "probe.interval_secs"),
+    ("PROBE_MODEL", "probe.model"),
+];
+
+/// 命令行参数
+#[derive(Parser, Debug)]
+#[command(version, about = "Atlassian AI 的 OpenAI 兼容代理")]
+pub struct Cli {
+    /// TOML 配置文件路径（默认读取当前目录下的 config.toml，若存在）
+    #[arg(long, short = 'c', global = true)]
+    pub config: Option<PathBuf>,
+    /// 监听地址，如 0.0.0.0:8080
+    #[arg(long, global = true)]
+    pub bind: Option<String>,
+    /// SQLite 数据库路径
+    #[arg(long, global = true)]
+    pub database_url: Option<String>,
+    /// Atlassian 上游地址，如 https://api.atlassian.com
+    #[arg(long, global = true)]
+    pub upstream_url: Option<String>,
+    /// 上游两次读取之间的最长等待（秒）
+    #[arg(long, global = true)]
+    pub upstream_timeout_secs: Option<u64>,
+    /// JWT 签名密钥文件，不存在时生成
+    #[arg(long, global = true)]
+    pub jwt_secret_file: Option<String>,
+    /// API Key 哈希密钥文件，不存在时生成
+    #[arg(long, global = true)]
+    pub api_key_secret_file: Option<String>,
+    /// 凭据加密主密钥文件
+    #[arg(long, global = true)]
+    pub master_key_file: Option<String>,
+    /// rotate-master-key 轮换到的新主密钥文件
+    #[arg(long, global = true)]
+    pub new_master_key_file: Option<String>,
+    /// Tera 模板目录
+    #[arg(long, global = true)]
+    pub template_dir: Option<String>,
+    /// 静态资源目录
+    #[arg(long, global = true)]
+    pub static_dir: Option<String>,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Clone, Deserialize, Default)]
+#[serde(default)]
+pub struct Settings {
+    pub server: ServerSettings,
+    pub database: DatabaseSettings,
+    pub upstream: UpstreamSettings,
+    pub probe: ProbeSettings,
+    pub login: LoginSettings,
+    pub auth: AuthSettings,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ServerSettings {
+    /// 监听地址；容器中需监听所有网卡
+    pub bind: String,
+    pub template_dir: String,
+    pub static_dir: String,
+    /// 管理后台 Cookie 带 `Secure` 属性，仅经 HTTPS 发送；通过 TLS（或终止 TLS 的反向代理）访问时应开启
+    pub secure_cookies: bool,
+}
+
+impl Default for ServerSettings {
+    fn default() -> Self {
+        Self { bind: "0.0.0.0:8080".into(), template_dir: "templates".into(), static_dir: "static".into(), secure_cookies: false }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct DatabaseSettings {
+    pub url: String,
+    pub pool_size: u32,
+}
+
+impl Default for DatabaseSettings {
+    fn default() -> Self {
+        Self { url: String::new(), pool_size: 8 }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct UpstreamSettings {
+    /// Atlassian API 根地址，不含路径
+    pub base_url: String,
+    pub connect_timeout_secs: u64,
+    /// 两次读取之间的最长等待；流式响应以此判断上游是否卡住
+    pub read_timeout_secs: u64,
+    /// 凭据选择策略，见 `selector::Strategy`
+    pub credential_strategy: String,
+}
+
+impl Default for UpstreamSettings {
+    fn default() -> Self {
+        Self {
+            base_url: "https://api.atlassian.com".into(),
+            connect_timeout_secs: 10,
+            read_timeout_secs: 300,
+            credential_strategy: "round_robin".into(),
+        }
+    }
+}
+
+impl UpstreamSettings {
+    /// 聊天补全接口的完整地址
+    pub fn chat_url(&self) -> String {
+        format!("{}/ai/chat/completions", self.base_url.trim_end_matches('/'))
+    }
+
+    pub fn connect_timeout(&self) -> Duration {
+        Duration::from_secs(self.connect_timeout_secs)
+    }
+
+    pub fn read_timeout(&self) -> Duration {
+        Duration::from_secs(self.read_timeout_secs)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ProbeSettings {
+    /// 凭据健康检查间隔，0 表示关闭
+    pub interval_secs: u64,
+    pub timeout_secs: u64,
+    /// 检查所用的模型别名，为空时取目录中的第一个模型
+    pub model: Option<String>,
+}
+
+impl Default for ProbeSettings {
+    fn default() -> Self {
+        Self { interval_secs: 300, timeout_secs: 30, model: None }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct LoginSettings {
+    /// 同一用户名或来源 IP 连续失败达到该次数后开始指数退避
+    pub free_attempts: u32,
+    pub backoff_base_secs: u64,
+    pub max_backoff_secs: u64,
+    /// 同一用户名连续失败达到该次数后临时锁定
+    pub max_failures: u32,
+    /// 同一来源 IP 连续失败达到该次数后临时锁定（IP 可能对应多个用户名，阈值更高）
+    pub ip_max_failures: u32,
+    /// 锁定时长；距最近一次失败超过该时长后失败计数清零
+    pub lockout_secs: u64,
+    /// 反向代理的地址或网段；TCP 对端属于其中之一时才从 `X-Forwarded-For` 读取来源 IP
+    #[serde(deserialize_with = "list_or_csv")]
+    pub trusted_proxies: Vec<IpRange>,
+}
+
+impl Default for LoginSettings {
+    fn default() -> Self {
+        Self {
+            free_attempts: 3,
+            backoff_base_secs: 1,
+            max_backoff_secs: 60,
+            max_failures: 5,
+            ip_max_failures: 20,
+            lockout_secs: 900,
+            trusted_proxies: Vec::new(),
+        }
+    }
+}
+
+impl LoginSettings {
+    /// 第 `failures` 次连续失败后，下一次尝试前需等待的时间
+    pub fn backoff(&self, failures: u32) -> Duration {
+        if failures < self.free_attempts {
+            return Duration::ZERO;
+        }
+        let exponent = (failures - self.free_attempts).min(16);
+        Duration::from_secs(self.backoff_base_secs.saturating_mul(1 << exponent).min(self.max_backoff_secs))
+    }
+
+    pub fn lockout(&self) -> Duration {
+        Duration::from_secs(self.lockout_secs)
+    }
+}
+
+/// IP 地址或 CIDR 网段，如 `10.0.0.1`、`172.16.0.0/12`、`fd00::/8`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpRange {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl IpRange {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let (net, ip, bits) = match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => (u32::from(net).into(), u32::from(ip).into(), 32),
+            (IpAddr::V6(net), IpAddr::V6(ip)) => (u128::from(net), u128::from(ip), 128),
+            _ => return false,
+        };
+        (net ^ ip).checked_shr(bits - u32::from(self.prefix)).unwrap_or(0) == 0
+    }
+}
+
+impl FromStr for IpRange {
+    type Err = String;
+
+    fn from_str(value: &str) -> std::result::Result<Self, Self::Err> {
+        let (addr, prefix) = value.split_once('/').map_or((value, None), |(addr, prefix)| (addr, Some(prefix)));
+        let addr = addr.parse::<IpAddr>().map_err(|_| format!("invalid IP address {value:?}"))?.to_canonical();
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse::<u8>().ok().filter(|p| *p <= max).ok_or_else(|| format!("invalid prefix length in {value:?}"))?,
+            None => max,
+        };
+        Ok(Self { addr, prefix })
+    }
+}
+
+/// 列表配置在 TOML 中写作数组，在环境变量中以逗号分隔
+fn list_or_csv<'de, D, T>(deserializer: D) -> std::result::Result<Vec<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: Display,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Raw {
+        List(Vec<String>),
+        Csv(String),
+    }
+    let items = match Raw::deserialize(deserializer)? {
+        Raw::List(items) => items,
+        Raw::Csv(value) => value.split(',').map(str::to_string).collect(),
+    };
+    items
+        .iter()
+        .map(|item| item.trim())
+        .filter(|item| !item.is_empty())
+        .map(|item| item.parse().map_err(de::Error::custom))
+        .collect()
+}
+
+#[derive(Clone, Deserialize, Default)]
+#[serde(default)]
+pub struct AuthSettings {
+    /// 管理员 JWT 的签名密钥（`kid` 为 `default`）
+    pub jwt_secret: Option<String>,
+    /// 未配置任何 JWT 密钥时，从该文件读取；文件不存在则生成随机密钥并写入
+    pub jwt_secret_file: Option<String>,
+    /// 带 `kid` 的 JWT 密钥，用于轮换：`jwt_active_kid` 对应的密钥签发，其余仅用于校验
+    pub jwt_keys: HashMap<String, String>,
+    pub jwt_active_kid: Option<String>,
+    /// API Key 哈希所用的 HMAC 密钥，与 JWT 密钥相互独立，轮换 JWT 密钥不影响已签发的 API Key
+    pub api_key_secret: Option<String>,
+    /// 未配置 `api_key_secret` 时从该文件读取；文件不存在则创建
+    pub api_key_secret_file: Option<String>,
+    /// 设置后 `/metrics` 需要 `Authorization: Bearer <token>`
+    pub metrics_token: Option<String>,
+    /// 凭据加密主密钥（base64），或通过 `master_key_file` 从文件读取
+    pub master_key: Option<String>,
+    pub master_key_file: Option<String>,
+    /// `rotate-master-key` 轮换到的新主密钥（base64），或通过 `new_master_key_file` 从文件读取
+    pub new_master_key: Option<String>,
+    pub new_master_key_file: Option<String>,
+    /// 开发模式：允许使用不安全的默认密钥与弱密钥启动
+    pub dev_mode: bool,
+}
+
+impl std::fmt::Debug for AuthSettings {
+    /// 不输出任何密钥内容
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let set = |v: &Option<String>| if v.is_some() { "<set>" } else { "<unset>" };
+        f.debug_struct("AuthSettings")
+            .field("jwt_secret", &set(&self.jwt_secret))
+            .field("jwt_secret_file", &self.jwt_secret_file)
+            .field("jwt_keys", &self.jwt_keys.keys().collect::<Vec<_>>())
+            .field("jwt_active_kid", &self.jwt_active_kid)
+            .field("api_key_secret", &set(&self.api_key_secret))
+            .field("api_key_secret_file", &self.api_key_secret_file)
+            .field("metrics_token", &set(&self.metrics_token))
+            .field("master_key", &set(&self.master_key))
+            .field("master_key_file", &self.master_key_file)
+            .field("new_master_key", &set(&self.new_master_key))
+            .field("new_master_key_file", &self.new_master_key_file)
+            .field("dev_mode", &self.dev_mode)
+            .finish()
+    }
+}
+
+/// 未配置 JWT 密钥时的旧版默认值，仅开发模式下可用
+pub const INSECURE_JWT_SECRET: &str = "secret";
+/// JWT 与 API Key 哈希密钥的最短长度（字节）
+const MIN_SECRET_LEN: usize = 32;
+/// 自动生成的 JWT 与 API Key 哈希密钥长度
+const GENERATED_SECRET_LEN: usize = 64;
+
+impl AuthSettings {
+    /// API Key 哈希密钥；仅开发模式下可能未设置，此时使用不安全的默认值
+    pub fn api_key_secret(&self) -> &str {
+        self.api_key_secret.as_deref().unwrap_or(INSECURE_JWT_SECRET)
+    }
+
+    /// 未配置任何 JWT 密钥时读取 `jwt_secret_file`，文件不存在则生成并持久化；返回是否新生成
+    fn load_jwt_secret_file(&mut self) -> Result<bool> {
+        let Some(path) = self.jwt_secret_file.as_deref() else {
+            return Ok(false);
+        };
+        if self.jwt_secret.is_some() || !self.jwt_keys.is_empty() {
+            return Ok(false);
+        }
+        let (secret, generated) = read_or_create_secret_file("JWT secret", Path::new(path), || utils::generate_random_password(GENERATED_SECRET_LEN))?;
+        self.jwt_secret = Some(secret);
+        Ok(generated)
+    }
+
+    /// 未配置 `api_key_secret` 时读取 `api_key_secret_file`，文件不存在则创建。
+    /// 旧版本以 `jwt_secret` 作为 API Key 哈希密钥，首次创建时沿用其当前值，已签发的 API Key 保持有效；
+    /// 之后两者各自独立，轮换 JWT 密钥不再影响 API Key。`jwt_secret_just_generated` 表示本次启动才生成 JWT 密钥（全新部署），此时直接生成随机密钥
+    fn load_api_key_secret_file(&mut self, jwt_secret_just_generated: bool) -> Result<()> {
+        let Some(path) = self.api_key_secret_file.as_deref() else {
+            return Ok(());
+        };
+        if self.api_key_secret.is_some() {
+            return Ok(());
+        }
+        let legacy = self.jwt_secret.clone().filter(|_| !jwt_secret_just_generated);
+        let seeded = legacy.is_some();
+        let (secret, generated) = read_or_create_secret_file("API key secret", Path::new(path), || {
+            legacy.unwrap_or_else(|| utils::generate_random_password(GENERATED_SECRET_LEN))
+        })?;
+        if generated && seeded {
+            log::warn!("Initialized {path} from the current JWT secret so existing API keys stay valid; the JWT secret can now be rotated independently");
+        }
+        self.api_key_secret = Some(secret);
+        Ok(())
+    }
+
+    /// 非开发模式下拒绝缺失、默认或过短的密钥
+    fn check_secrets(&self) -> Result<()> {
+        if self.jwt_secret.is_none() && self.jwt_keys.is_empty() {
+            if !self.dev_mode {
+                bail!("auth.jwt_secret, auth.jwt_keys or auth.jwt_secret_file must be set (e.g. JWT_SECRET or JWT_SECRET_FILE); set auth.dev_mode = true to use an insecure default for local development");
+            }
+            log::warn!("auth.dev_mode is enabled and no JWT secret is set; using an insecure default secret");
+        }
+        if self.api_key_secret.is_none() {
+            if !self.dev_mode {
+                bail!("auth.api_key_secret or auth.api_key_secret_file must be set (e.g. API_KEY_SECRET or API_KEY_SECRET_FILE); it must not change when JWT secrets are rotated");
+            }
+            log::warn!("auth.dev_mode is enabled and no API key secret is set; using an insecure default secret");
+        }
+
+        let secrets = self
+            .jwt_secret
+            .iter()
+            .map(|s| ("auth.jwt_secret".to_string(), s))
+            .chain(self.jwt_keys.iter().map(|(kid, s)| (format!("auth.jwt_keys.{kid}"), s)))
+            .chain(self.api_key_secret.iter().map(|s| ("auth.api_key_secret".to_string(), s)));
+        for (name, secret) in secrets {
+            if secret == INSECURE_JWT_SECRET || secret.len() < MIN_SECRET_LEN {
+                if !self.dev_mode {
+                    bail!("{name} must be at least {MIN_SECRET_LEN} bytes and not a default value (generate one with `openssl rand -base64 48`)");
+                }
+                log::warn!("{name} is weak; accepted only because auth.dev_mode is enabled");
+            }
+        }
+        Ok(())
+    }
+}
+
+/// 读取密钥文件；文件不存在时以 `initial` 创建（权限 0600），返回密钥及是否新建
+fn read_or_create_secret_file(what: &str, path: &Path, initial: impl FnOnce() -> String) -> Result<(String, bool)> {
+    if path.exists() {
+        let secret = std::fs::read_to_string(path).with_context(|| format!("Failed to read {what} file {}", path.display()))?;
+        return Ok((secret.trim().to_string(), false));
+    }
+
+    let secret = initial();
+    if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+        std::fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+    }
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)
+        .with_context(|| format!("Failed to create {what} file {}", path.display()))?;
+    file.write_all(secret.as_bytes())?;
+    log::info!("Generated a new {what} in {}", path.display());
+    Ok((secret, true))
+}
+
+impl Settings {
+    /// 按优先级合并各来源的配置
+    pub fn load(cli: &Cli) -> Result<Self> {
+        dotenv::dotenv().ok();
+
+        let mut builder = Config::builder();
+        builder = match &cli.config {
+            Some(path) => builder.add_source(File::from(path.as_path()).required(true)),
+            None => builder.add_source(File::with_name(DEFAULT_CONFIG_FILE).required(false)),
+        };
+
+        let legacy: HashMap<String, String> = LEGACY_ENV
+            .iter()
+            .filter_map(|(var, key)| {
+                let value = std::env::var(var).ok()?;
+                Some((key.replace('.', "__"), value))
+            })
+            .collect();
+        builder = builder
+            .add_source(Environment::default().source(Some(legacy)).separator("__"))
+            // 例如 APP_SERVER__BIND、APP_UPSTREAM__BASE_URL
+            .add_source(Environment::with_prefix("APP").prefix_separator("_").separator("__"));
+
+        let mut settings: Settings = builder
+            .set_override_option("server.bind", cli.bind.clone())?
+            .set_override_option("server.template_dir", cli.template_dir.clone())?
+            .set_override_option("server.static_dir", cli.static_dir.clone())?
+            .set_override_option("database.url", cli.database_url.clone())?
+            .set_override_option("upstream.base_url", cli.upstream_url.clone())?
+            .set_override_option("upstream.read_timeout_secs", cli.upstream_timeout_secs)?
+            .set_override_option("auth.jwt_secret_file", cli.jwt_secret_file.clone())?
+            .set_override_option("auth.api_key_secret_file", cli.api_key_secret_file.clone())?
+            .set_override_option("auth.master_key_file", cli.master_key_file.clone())?
+            .set_override_option("auth.new_master_key_file", cli.new_master_key_file.clone())?
+            .build()
+            .context("Failed to load configuration")?
+            .try_deserialize()
+            .context("Invalid configuration")?;
+
+        if settings.database.url.is_empty() {
+            anyhow::bail!("database.url must be set (config file, DATABASE_URL, APP_DATABASE__URL or --database-url)");
+        }
+        let jwt_secret_generated = settings.auth.load_jwt_secret_file()?;
+        settings.auth.load_api_key_secret_file(jwt_secret_generated)?;
+        settings.auth.check_secrets()?;
+        Ok(settings)
+    }
+}
@@ -0,0 +1,427 @@
+//! 将 Atlassian 上游的流式输出转换为 OpenAI `chat.completion.chunk` SSE 事件
+
+use std::collections::{HashSet, VecDeque};
+use std::pin::Pin;
+use std::time::Instant;
+
+use actix_web::web::Bytes;
+use chrono::Utc;
+use futures_util::{stream, Stream, StreamExt};
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::handlers::{AtlassianResponse, Usage};
+use crate::metrics;
+
+#[derive(Serialize)]
+pub struct ChatCompletionChunk {
+    id: String,
+    object: &'static str,
+    created: i64,
+    model: String,
+    choices: Vec<ChunkChoice>,
+}
+
+#[derive(Serialize)]
+pub struct ChunkChoice {
+    index: u32,
+    delta: ChunkDelta,
+    finish_reason: Option<String>,
+}
+
+#[derive(Serialize, Default)]
+pub struct ChunkDelta {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    role: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content: Option<String>,
+}
+
+/// 增量解析上游事件流，并生成 OpenAI 格式的 SSE 帧
+pub struct ChunkTranslator {
+    id: String,
+    created: i64,
+    model: String,
+    line_buf: Vec<u8>,
+    data_lines: Vec<String>,
+    /// 已发送过 `delta.role` 的 choice 序号；`n > 1` 时每个 choice 的首个分片都要带角色
+    role_sent: HashSet<u32>,
+    finish_sent: bool,
+    done: bool,
+    usage: Option<Usage>,
+    completion_chars: usize,
+}
+
+impl ChunkTranslator {
+    pub fn new(model: String) -> Self {
+        Self {
+            id: format!("chatcmpl-{}", Uuid::new_v4().simple()),
+            created: Utc::now().timestamp(),
+            model,
+            line_buf: Vec::new(),
+            data_lines: Vec::new(),
+            role_sent: HashSet::new(),
+            finish_sent: false,
+            done: false,
+            usage: None,
+            completion_chars: 0,
+        }
+    }
+
+    /// 本次流的 Token 用量：优先采用上游报告的数值，否则按已输出内容估算
+    pub fn usage(&self, prompt_tokens: u32) -> Usage {
+        self.usage.unwrap_or_default().or_estimate(prompt_tokens, self.completion_chars)
+    }
+
+    /// 输入一段上游字节，返回由此产生的完整 SSE 帧
+    pub fn feed(&mut self, bytes: &[u8]) -> Vec<Bytes> {
+        let mut frames = Vec::new();
+        self.line_buf.extend_from_slice(bytes);
+        while let Some(pos) = self.line_buf.iter().position(|b| *b == b'\n') {
+            let raw: Vec<u8> = self.line_buf.drain(..=pos).collect();
+            let line = String::from_utf8_lossy(&raw);
+            self.handle_line(line.trim_end_matches(['\r', '\n']), &mut frames);
+        }
+        frames
+    }
+
+    /// 上游结束：冲刷残留数据，补发结束帧与 `[DONE]`
+    pub fn finish(&mut self) -> Vec<Bytes> {
+        let mut frames = Vec::new();
+        if !self.line_buf.is_empty() {
+            let raw = std::mem::take(&mut self.line_buf);
+            let line = String::from_utf8_lossy(&raw).into_owned();
+            self.handle_line(line.trim_end_matches('\r'), &mut frames);
+        }
+        self.dispatch_event(&mut frames);
+        if !self.finish_sent {
+            frames.push(self.frame(ChunkDelta::default(), Some("stop".into())));
+            self.finish_sent = true;
+        }
+        frames.push(Bytes::from_static(b"data: [DONE]\n\n"));
+        frames
+    }
+
+    /// 上游中途出错：只发送一个错误事件，不补发结束帧与 `[DONE]`，避免客户端把截断的回答当作正常完成。
+    /// 尚未收完的事件直接丢弃
+    pub fn abort(&mut self, message: &str) -> Vec<Bytes> {
+        self.line_buf.clear();
+        self.data_lines.clear();
+        self.done = true;
+        let error = serde_json::json!({
+            "error": { "message": message, "type": "upstream_error", "param": null, "code": "stream_interrupted" }
+        });
+        vec![Bytes::from(format!("data: {error}\n\n"))]
+    }
+
+    fn handle_line(&mut self, line: &str, frames: &mut Vec<Bytes>) {
+        if line.is_empty() {
+            self.dispatch_event(frames);
+        } else if let Some(data) = line.strip_prefix("data:") {
+            self.data_lines.push(data.strip_prefix(' ').unwrap_or(data).to_string());
+        } else if line.starts_with('{') {
+            // 兼容以换行分隔的 JSON（NDJSON）流
+            self.dispatch_event(frames);
+            self.data_lines.push(line.to_string());
+            self.dispatch_event(frames);
+        }
+        // 其余字段（event:、id:、retry:、注释行）无需转发
+    }
+
+    fn dispatch_event(&mut self, frames: &mut Vec<Bytes>) {
+        if self.data_lines.is_empty() {
+            return;
+        }
+        let data = self.data_lines.join("\n");
+        self.data_lines.clear();
+        if self.done {
+            return;
+        }
+        if data.trim() == "[DONE]" {
+            self.done = true;
+            return;
+        }
+
+        let payload = match serde_json::from_str::<AtlassianResponse>(&data) {
+            Ok(event) => event.into_payload(),
+            Err(e) => {
+                log::warn!("Skipping unparsable upstream event: {e}");
+                return;
+            }
+        };
+
+        if let Some(usage) = payload.usage() {
+            self.usage = Some(usage);
+        }
+        for choice in payload.choices {
+            let finish_reason = choice.finish_reason();
+            let content = choice.text().filter(|t| !t.is_empty());
+            self.completion_chars += content.as_ref().map_or(0, |t| t.chars().count());
+            if content.is_none() && finish_reason.is_none() {
+                continue;
+            }
+            let role = self.role_sent.insert(choice.index).then(|| choice.role().unwrap_or("assistant").to_string());
+            if finish_reason.is_some() {
+                self.finish_sent = true;
+            }
+            let frame = ChunkChoice { index: choice.index, delta: ChunkDelta { role, content }, finish_reason };
+            frames.push(self.encode(vec![frame]));
+        }
+    }
+
+    fn frame(&self, delta: ChunkDelta, finish_reason: Option<String>) -> Bytes {
+        self.encode(vec![ChunkChoice { index: 0, delta, finish_reason }])
+    }
+
+    fn encode(&self, choices: Vec<ChunkChoice>) -> Bytes {
+        let chunk = ChatCompletionChunk {
+            id: self.id.clone(),
+            object: "chat.completion.chunk",
+            created: self.created,
+            model: self.model.clone(),
+            choices,
+        };
+        let json = serde_json::to_string(&chunk).unwrap_or_default();
+        Bytes::from(format!("data: {json}\n\n"))
+    }
+}
+
+type UpstreamStream = Pin<Box<dyn Stream<Item = reqwest::Result<Bytes>>>>;
+/// 流结束回调：Token 用量与上游中途出错的原因
+type OnEnd = Box<dyn FnOnce(Usage, Option<String>)>;
+
+struct TranslateState {
+    upstream: UpstreamStream,
+    translator: ChunkTranslator,
+    pending: VecDeque<Bytes>,
+    ended: bool,
+    prompt_tokens: u32,
+    on_end: Option<OnEnd>,
+    /// 上游中途出错的原因
+    error: Option<String>,
+    /// 请求到达时刻，用于统计首 Token 时间
+    started: Instant,
+    model: String,
+    first_token_seen: bool,
+}
+
+impl Drop for TranslateState {
+    /// 流正常结束或客户端中途断开时都会调用一次结束回调
+    fn drop(&mut self) {
+        if let Some(on_end) = self.on_end.take() {
+            on_end(self.translator.usage(self.prompt_tokens), self.error.take());
+        }
+    }
+}
+
+/// 将上游字节流包装为 OpenAI 兼容的 SSE 流；流结束后以本次 Token 用量调用 `on_end`，
+/// 上游中途出错时第二个参数为错误原因
+pub fn translate<S, F>(upstream: S, model: String, started: Instant, prompt_tokens: u32, on_end: F) -> impl Stream<Item = Result<Bytes, actix_web::Error>>
+where
+    S: Stream<Item = reqwest::Result<Bytes>> + 'static,
+    F: FnOnce(Usage, Option<String>) + 'static,
+{
+    let state = TranslateState {
+        upstream: Box::pin(upstream),
+        translator: ChunkTranslator::new(model.clone()),
+        pending: VecDeque::new(),
+        ended: false,
+        prompt_tokens,
+        on_end: Some(Box::new(on_end)),
+        error: None,
+        started,
+        model,
+        first_token_seen: false,
+    };
+
+    stream::unfold(state, |mut state| async move {
+        loop {
+            if let Some(frame) = state.pending.pop_front() {
+                return Some((Ok(frame), state));
+            }
+            if state.ended {
+                return None;
+            }
+            match state.upstream.next().await {
+                Some(Ok(bytes)) => {
+                    let frames = state.translator.feed(&bytes);
+                    if !state.first_token_seen && !frames.is_empty() {
+                        state.first_token_seen = true;
+                        metrics::TIME_TO_FIRST_TOKEN
+                            .with_label_values(&[state.model.as_str()])
+                            .observe(state.started.elapsed().as_secs_f64());
+                    }
+                    state.pending.extend(frames);
+                }
+                Some(Err(e)) => {
+                    log::error!("Upstream stream aborted: {e}");
+                    let frames = state.translator.abort("The upstream response was interrupted");
+                    state.pending.extend(frames);
+                    state.error = Some(format!("Upstream stream aborted: {e}"));
+                    state.ended = true;
+                }
+                None => {
+                    let frames = state.translator.finish();
+                    state.pending.extend(frames);
+                    state.ended = true;
+                }
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+
+    use super::*;
+
+    /// 把一组 SSE 帧拆回 `data:` 负载；`[DONE]` 原样保留为字符串
+    fn events(frames: &[Bytes]) -> Vec<Value> {
+        frames
+            .iter()
+            .map(|frame| {
+                let text = std::str::from_utf8(frame).unwrap();
+                let data = text.strip_prefix("data: ").and_then(|t| t.strip_suffix("\n\n")).expect("malformed SSE frame");
+                serde_json::from_str(data).unwrap_or_else(|_| Value::String(data.into()))
+            })
+            .collect()
+    }
+
+    fn contents(events: &[Value]) -> String {
+        events.iter().filter_map(|e| e["choices"][0]["delta"]["content"].as_str()).collect()
+    }
+
+    #[test]
+    fn translates_sse_events_and_done() {
+        let mut t = ChunkTranslator::new("m".into());
+        let mut frames = t.feed(b"event: message\ndata: {\"choices\":[{\"delta\":{\"content\":\"Hel\"}}]}\n\n");
+        frames.extend(t.feed(b"data: {\"choices\":[{\"delta\":{\"content\":\"lo\"},\"finish_reason\":\"end_turn\"}]}\n\ndata: [DONE]\n\n"));
+        frames.extend(t.finish());
+
+        let events = events(&frames);
+        assert_eq!(events.len(), 3);
+        assert_eq!(events[0]["object"], "chat.completion.chunk");
+        assert_eq!(events[0]["model"], "m");
+        assert_eq!(events[0]["choices"][0]["delta"]["role"], "assistant");
+        assert!(events[1]["choices"][0]["delta"].get("role").is_none());
+        assert_eq!(events[1]["choices"][0]["finish_reason"], "stop");
+        assert_eq!(contents(&events), "Hello");
+        assert_eq!(events[2], "[DONE]");
+    }
+
+    #[test]
+    fn sends_role_once_per_choice() {
+        let mut t = ChunkTranslator::new("m".into());
+        let frames = t.feed(
+            b"data: {\"choices\":[{\"index\":0,\"delta\":{\"content\":\"a\"}},{\"index\":1,\"delta\":{\"content\":\"b\"}}]}\n\n\
+              data: {\"choices\":[{\"index\":0,\"delta\":{\"content\":\"c\"}},{\"index\":1,\"delta\":{\"content\":\"d\"}}]}\n\n",
+        );
+        let events = events(&frames);
+        let roles: Vec<(&Value, &Value)> = events.iter().map(|e| (&e["choices"][0]["index"], &e["choices"][0]["delta"]["role"])).collect();
+        assert_eq!(roles, [(&0.into(), &"assistant".into()), (&1.into(), &"assistant".into()), (&0.into(), &Value::Null), (&1.into(), &Value::Null)]);
+    }
+
+    #[test]
+    fn reassembles_events_split_across_reads() {
+        let input: &[u8] = b"data: {\"choices\":[{\"delta\":{\"content\":\"a\xc3\xa9\"}}]}\r\n\r\ndata: {\"choices\":[{\"delta\":{\"content\":\"b\"}}]}\n\n";
+        let mut t = ChunkTranslator::new("m".into());
+        // 逐字节输入，覆盖在行中间、多字节字符中间以及 `\r\n` 之间断开的情况
+        let mut frames: Vec<Bytes> = input.iter().flat_map(|b| t.feed(std::slice::from_ref(b))).collect();
+        frames.extend(t.finish());
+
+        let events = events(&frames);
+        assert_eq!(contents(&events), "aéb");
+        assert_eq!(events.last().unwrap(), "[DONE]");
+        // 上游未给出结束原因时补发 stop
+        assert_eq!(events[events.len() - 2]["choices"][0]["finish_reason"], "stop");
+    }
+
+    #[test]
+    fn joins_multi_line_data_fields() {
+        let mut t = ChunkTranslator::new("m".into());
+        let frames = t.feed(b"data: {\"choices\":[{\"delta\":\ndata: {\"content\":\"x\"}}]}\n\n");
+        assert_eq!(contents(&events(&frames)), "x");
+    }
+
+    #[test]
+    fn translates_ndjson_lines() {
+        let mut t = ChunkTranslator::new("m".into());
+        let mut frames = t.feed(b"{\"response_payload\":{\"choices\":[{\"message\":{\"content\":[{\"text\":\"one\"}]}}]}}\n{\"choices\":[{\"delta\":{\"content\":\" two\"}}],");
+        assert_eq!(frames.len(), 1);
+        // 最后一行没有换行符，结束时仍要解析
+        frames.extend(t.feed(b"\"usage\":{\"input_tokens\":3,\"output_tokens\":2,\"total_tokens\":5}}"));
+        frames.extend(t.finish());
+
+        let events = events(&frames);
+        assert_eq!(contents(&events), "one two");
+        assert_eq!(events.last().unwrap(), "[DONE]");
+        let usage = t.usage(100);
+        assert_eq!((usage.prompt_tokens, usage.completion_tokens, usage.total_tokens), (3, 2, 5));
+    }
+
+    #[test]
+    fn ignores_events_after_done() {
+        let mut t = ChunkTranslator::new("m".into());
+        let mut frames = t.feed(b"data: [DONE]\n\ndata: {\"choices\":[{\"delta\":{\"content\":\"late\"}}]}\n\n");
+        assert!(frames.is_empty());
+        frames.extend(t.finish());
+        let events = events(&frames);
+        assert_eq!(contents(&events), "");
+        assert_eq!(events.last().unwrap(), "[DONE]");
+    }
+
+    #[test]
+    fn skips_unparsable_events() {
+        let mut t = ChunkTranslator::new("m".into());
+        let frames = t.feed(b": keep-alive\ndata: not json\n\ndata: {\"choices\":[{\"delta\":{\"content\":\"ok\"}}]}\n\n");
+        assert_eq!(contents(&events(&frames)), "ok");
+    }
+
+    #[test]
+    fn estimates_usage_when_upstream_reports_none() {
+        let mut t = ChunkTranslator::new("m".into());
+        t.feed(b"data: {\"choices\":[{\"delta\":{\"content\":\"hello world\"}}]}\n\n");
+        let usage = t.usage(7);
+        assert_eq!(usage.prompt_tokens, 7);
+        assert!(usage.completion_tokens > 0);
+        assert_eq!(usage.total_tokens, usage.prompt_tokens + usage.completion_tokens);
+    }
+
+    #[test]
+    fn abort_sends_error_without_stop_or_done() {
+        let mut t = ChunkTranslator::new("m".into());
+        let mut frames = t.feed(b"data: {\"choices\":[{\"delta\":{\"content\":\"partial\"}}]}\n\ndata: {\"choices\"");
+        frames.extend(t.abort("boom"));
+
+        let events = events(&frames);
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[1]["error"]["message"], "boom");
+        assert_eq!(events[1]["error"]["code"], "stream_interrupted");
+        assert!(events.iter().all(|e| e != "[DONE]" && e["choices"][0]["finish_reason"].is_null()));
+    }
+
+    #[actix_web::test]
+    async fn stream_reports_upstream_error_to_on_end() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        let upstream_error = reqwest::Client::new().get("not a url").send().await.unwrap_err();
+        let upstream = stream::iter(vec![Ok(Bytes::from_static(b"data: {\"choices\":[{\"delta\":{\"content\":\"hi\"}}]}\n\n")), Err(upstream_error)]);
+        let ended = Rc::new(RefCell::new(None));
+        let sink = ended.clone();
+        let frames: Vec<Bytes> = translate(upstream, "m".into(), Instant::now(), 1, move |usage, error| {
+            *sink.borrow_mut() = Some((usage.prompt_tokens, error));
+        })
+        .map(Result::unwrap)
+        .collect()
+        .await;
+
+        let events = events(&frames);
+        assert_eq!(contents(&events), "hi");
+        assert_eq!(events.last().unwrap()["error"]["code"], "stream_interrupted");
+        let (prompt_tokens, error) = ended.borrow_mut().take().expect("on_end not called");
+        assert_eq!(prompt_tokens, 1);
+        assert!(error.is_some());
+    }
+}
@@ -3,8 +3,17 @@ use argon2::{
     password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
     Argon2,
 };
+use hmac::{Hmac, Mac};
 use rand::{distributions::Alphanumeric, Rng};
 use rand_core::OsRng;
+use sha2::Sha256;
+
+/// API Key 固定前缀
+pub const API_KEY_PREFIX: &str = "sk-atl-";
+/// 数据库中保存并展示的可见前缀长度
+pub const API_KEY_VISIBLE_LEN: usize = 12;
+/// API Key 中随机部分的长度
+const API_KEY_RANDOM_LEN: usize = 40;
 
 
 /// 对给定明文密码生成 Argon2 哈希
@@ -26,6 +35,29 @@ pub fn verify_password(hash: &str, password: &str) -> Result<bool> {
         .is_ok())
 }
 
+/// 管理员密码的最短长度
+pub const MIN_PASSWORD_LEN: usize = 12;
+
+/// 密码强度策略：至少 12 个字符，包含大写字母、小写字母、数字、符号中的至少三类，且不包含用户名
+pub fn check_password_strength(username: &str, password: &str) -> Result<(), &'static str> {
+    if password.chars().count() < MIN_PASSWORD_LEN {
+        return Err("密码至少需要 12 个字符");
+    }
+    let classes = [
+        password.chars().any(|c| c.is_ascii_lowercase()),
+        password.chars().any(|c| c.is_ascii_uppercase()),
+        password.chars().any(|c| c.is_ascii_digit()),
+        password.chars().any(|c| !c.is_ascii_alphanumeric()),
+    ];
+    if classes.iter().filter(|c| **c).count() < 3 {
+        return Err("密码需包含大写字母、小写字母、数字、符号中的至少三类");
+    }
+    if !username.is_empty() && password.to_lowercase().contains(&username.to_lowercase()) {
+        return Err("密码不能包含用户名");
+    }
+    Ok(())
+}
+
 /// 生成指定长度的随机密码
 pub fn generate_random_password(len: usize) -> String {
     rand::thread_rng()
@@ -34,3 +66,39 @@ pub fn generate_random_password(len: usize) -> String {
         .map(char::from)
         .collect()
 }
+
+/// 生成新的 API Key 明文，如 `sk-atl-3fZk…`
+pub fn generate_api_key() -> String {
+    let random: String = OsRng
+        .sample_iter(&Alphanumeric)
+        .take(API_KEY_RANDOM_LEN)
+        .map(char::from)
+        .collect();
+    format!("{API_KEY_PREFIX}{random}")
+}
+
+/// API Key 的可见前缀
+pub fn api_key_prefix(key: &str) -> String {
+    key.chars().take(API_KEY_VISIBLE_LEN).collect()
+}
+
+fn api_key_mac(secret: &str) -> Hmac<Sha256> {
+    Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length")
+}
+
+/// 计算 API Key 的 HMAC-SHA256（十六进制），`secret` 为 `auth.api_key_secret`
+pub fn hash_api_key(secret: &str, key: &str) -> String {
+    let mut mac = api_key_mac(secret);
+    mac.update(key.as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+/// 以常量时间比较 API Key 与已保存的哈希
+pub fn verify_api_key(secret: &str, key: &str, expected_hex: &str) -> bool {
+    let Ok(expected) = hex::decode(expected_hex) else {
+        return false;
+    };
+    let mut mac = api_key_mac(secret);
+    mac.update(key.as_bytes());
+    mac.verify_slice(&expected).is_ok()
+}